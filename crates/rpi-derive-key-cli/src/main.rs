@@ -1,19 +1,40 @@
 #![allow(clippy::uninlined_format_args)] // Required because MSRV = 1.65.
 
-use std::fmt::Write;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
 
-use clap::{Parser, Subcommand};
-use rpi_derive_key::DeriverBuilder;
+use clap::{Parser, Subcommand, ValueEnum};
+use rpi_derive_key::{
+    BuildError, Deriver, DeriverBuilder, Plan, SecretLocation, UuidVersion, MAX_KEY_LEN,
+};
+use thiserror::Error;
 use uuid::Uuid;
+use zeroize::Zeroizing;
 
 /// The command line arguments.
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about)]
-
 struct Args {
     /// Use the customer OTP values for storing the device secret.
     #[clap(long)]
     customer_otp: bool,
+    /// An optional salt to use for the HKDF algorithm.
+    #[clap(long, global = true)]
+    salt: Option<String>,
+    /// An optional hex-encoded salt to use for the HKDF algorithm (for binary salts).
+    #[clap(long, global = true, conflicts_with = "salt")]
+    salt_hex: Option<String>,
+    /// Print the status as JSON.
+    #[clap(long, global = true)]
+    json: bool,
+    /// Use the provided fake device secret given as 64 hex characters (for testing only).
+    ///
+    /// This is an alternative to the `FAKE_RPI_DERIVE_KEY_SECRET` environment variable.
+    #[clap(long, global = true, hide = true, value_name = "HEX", value_parser = parse_fake_secret)]
+    fake_secret: Option<[u8; 32]>,
     /// Subcommand of the CLI.
     #[command(subcommand)]
     cmd: Command,
@@ -21,84 +42,629 @@ struct Args {
 
 /// Subcommands of the CLI.
 #[derive(Subcommand, Debug, Clone)]
-
 enum Command {
     /// Print the status of the OTP registers and key derivation mechanism.
     Status,
+    /// Check whether the device secret has been initialized.
+    ///
+    /// Exits with a non-zero status code in case the device secret is not usable.
     Check,
     /// Irreversibly initialize the OTP registers of the Raspberry Pi.
+    ///
+    /// Without `--yes`, only describes what would be done.
     Init {
+        /// The location of the device secret (overrides `--customer-otp`).
+        #[clap(long, value_enum)]
+        location: Option<SecretLocationArg>,
+        /// Confirm irreversibly writing the device secret to the OTP registers.
+        #[clap(long)]
+        yes: bool,
         /// Use the supplied group secret for the upper 128-bits of the device secret.
         ///
         /// Can be used in a challenge-response handshake to show that the RPi belongs to
         /// a certain group of devices. Furthermore, group secrets enable the derivation
-        /// of shared secrets for devices in the same group.
-        group_secret: Uuid,
+        /// of shared secrets for devices in the same group. None of the four 32-bit
+        /// words of the group secret must be zero.
+        group_secret: Option<Uuid>,
     },
+    /// Derive a hardware-specific key (of 32 bytes by default) using the provided
+    /// information.
     Derive {
-        /// An optional salt to use for the HKDF algorithm.
-        #[clap(long)]
-        salt: Option<String>,
         /// Use only the group secret for the derivation.
+        ///
+        /// Fails in case the device secret has no group secret (see `group`).
         #[clap(long)]
         group_only: bool,
-        /// Additional information used to derive the key.
-        info: String,
+        /// The size of the key in bytes.
+        #[clap(long, default_value_t = DERIVE_KEY_SIZE)]
+        bytes: usize,
+        /// The encoding used for printing the key.
+        #[clap(long, value_enum, default_value_t)]
+        encoding: Encoding,
+        /// Write the raw bytes of the key to stdout (without any encoding).
+        #[clap(long, conflicts_with = "encoding")]
+        raw: bool,
+        /// Derive the given number of keys from the info material and an index.
+        ///
+        /// The info material and the index, a 32-bit big-endian integer, are each
+        /// prefixed with their length in bytes as a 32-bit big-endian integer and
+        /// concatenated. For instance, key 1 for the info material `ab` is derived from
+        /// `\x00\x00\x00\x02ab\x00\x00\x00\x04\x00\x00\x00\x01`. The keys are printed
+        /// one per line in the order of their indices starting at zero.
+        #[clap(long, conflicts_with = "raw")]
+        count: Option<u32>,
+        /// Write the key to the given file instead of stdout.
+        ///
+        /// The file is created with permissions `0600` and must not exist.
+        #[clap(long)]
+        output: Option<PathBuf>,
+        #[command(flatten)]
+        info: InfoArgs,
+    },
+    /// Derive a group key (of 32 bytes by default) shared by all devices with the same
+    /// group secret using the provided information.
+    ///
+    /// Fails in case the device secret has no group secret, i.e., its upper 128-bits are
+    /// zero.
+    Group {
+        /// The size of the key in bytes.
+        #[clap(long, default_value_t = DERIVE_KEY_SIZE)]
+        bytes: usize,
+        /// The encoding used for printing the key.
+        #[clap(long, value_enum, default_value_t)]
+        encoding: Encoding,
+        #[command(flatten)]
+        info: InfoArgs,
     },
     /// Derive a hardware-specific key using the provided information.
     Hex {
         /// The size of the key in bytes.
         bytes: u16,
-        /// Additional information used to derive the key.
-        info: String,
+        /// The encoding used for printing the key.
+        #[clap(long, value_enum, default_value_t)]
+        encoding: Encoding,
+        /// Write the raw bytes of the key to stdout (without any encoding).
+        #[clap(long, conflicts_with = "encoding")]
+        raw: bool,
+        /// Derive the given number of keys from the info material and an index.
+        ///
+        /// The info material and the index, a 32-bit big-endian integer, are each
+        /// prefixed with their length in bytes as a 32-bit big-endian integer and
+        /// concatenated. For instance, key 1 for the info material `ab` is derived from
+        /// `\x00\x00\x00\x02ab\x00\x00\x00\x04\x00\x00\x00\x01`. The keys are printed
+        /// one per line in the order of their indices starting at zero.
+        #[clap(long, conflicts_with = "raw")]
+        count: Option<u32>,
+        /// Write the key to the given file instead of stdout.
+        ///
+        /// The file is created with permissions `0600` and must not exist.
+        #[clap(long)]
+        output: Option<PathBuf>,
+        #[command(flatten)]
+        info: InfoArgs,
     },
-    /// Derives a UUID version 4 using the provided info material.
+    /// Derive an Ed25519 key using the provided information and print its public key.
+    ///
+    /// Intended for enrolling the device with a server. The private key never leaves
+    /// the device and is never printed.
+    ExportPublic {
+        /// The encoding used for printing the public key.
+        #[clap(long, value_enum, default_value_t)]
+        encoding: Encoding,
+        #[command(flatten)]
+        info: InfoArgs,
+    },
+    /// Derives a UUID using the provided info material.
+    ///
+    /// The UUID is derived deterministically and only follows the layout of the given
+    /// UUID version.
     Uuid {
-        info: String,
+        /// The version of the UUID layout.
+        #[clap(long, value_enum, default_value_t)]
+        version: UuidVersionArg,
+        #[command(flatten)]
+        info: InfoArgs,
     },
 }
 
-fn main() {
-    let args = Args::parse();
+/// The info material used to derive a key.
+#[derive(clap::Args, Debug, Clone)]
+struct InfoArgs {
+    /// Additional information used to derive the key.
+    #[clap(
+        required_unless_present_any = ["info_file", "info_stdin"],
+        conflicts_with_all = ["info_file", "info_stdin"]
+    )]
+    info: Option<String>,
+    /// Read the additional information from the provided file.
+    #[clap(long, conflicts_with = "info_stdin")]
+    info_file: Option<PathBuf>,
+    /// Read the additional information from stdin.
+    #[clap(long)]
+    info_stdin: bool,
+}
 
-    let builder = DeriverBuilder::new()
-        // .with_salt(args.salt)
-        .with_use_customer_otp(args.customer_otp);
+impl InfoArgs {
+    /// Reads the info material from the source specified by the arguments.
+    fn read(&self) -> io::Result<Vec<u8>> {
+        if let Some(info) = &self.info {
+            Ok(info.as_bytes().to_vec())
+        } else if let Some(path) = &self.info_file {
+            std::fs::read(path)
+        } else {
+            let mut info = Vec::new();
+            io::stdin().read_to_end(&mut info)?;
+            Ok(info)
+        }
+    }
+}
 
-    match args.cmd {
-        Command::Status => {
-            let status = rpi_derive_key::status().unwrap();
-            println!("Has Customer OTP: {}", status.has_customer_otp);
-            println!("Has Private Key: {}", status.has_private_key);
+/// Encodings for printing derived keys.
+#[derive(ValueEnum, Debug, Clone, Copy, Default)]
+enum Encoding {
+    /// Lowercase hex encoding.
+    #[default]
+    Hex,
+    /// Standard base64 encoding with padding (RFC 4648).
+    Base64,
+    /// URL-safe base64 encoding without padding (RFC 4648).
+    #[value(name = "base64url")]
+    Base64Url,
+    /// Base32 encoding with padding (RFC 4648).
+    Base32,
+}
+
+impl Encoding {
+    /// Encodes the provided bytes.
+    fn encode(self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Hex => data_encoding::HEXLOWER.encode(bytes),
+            Encoding::Base64 => data_encoding::BASE64.encode(bytes),
+            Encoding::Base64Url => data_encoding::BASE64URL_NOPAD.encode(bytes),
+            Encoding::Base32 => data_encoding::BASE32.encode(bytes),
         }
-        Command::Init { .. } => {
-            builder.initialize(true).build().unwrap();
-            let status = rpi_derive_key::status().unwrap();
-            println!("Has Customer OTP: {}", status.has_customer_otp);
-            println!("Has Private Key: {}", status.has_private_key);
+    }
+}
+
+/// Locations for storing the device secret.
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum SecretLocationArg {
+    /// The private key OTP registers.
+    PrivateKey,
+    /// The customer-programmable OTP registers.
+    CustomerOtp,
+    /// Detect the location automatically (preferring the private key).
+    Auto,
+}
+
+impl From<SecretLocationArg> for SecretLocation {
+    fn from(location: SecretLocationArg) -> Self {
+        match location {
+            SecretLocationArg::PrivateKey => SecretLocation::PrivateKey,
+            SecretLocationArg::CustomerOtp => SecretLocation::CustomerOtp,
+            SecretLocationArg::Auto => SecretLocation::Auto,
+        }
+    }
+}
+
+/// UUID versions for deriving UUIDs.
+#[derive(ValueEnum, Debug, Clone, Copy, Default)]
+enum UuidVersionArg {
+    /// Version 4 layout.
+    #[default]
+    #[value(name = "4")]
+    V4,
+    /// Version 5 layout.
+    #[value(name = "5")]
+    V5,
+    /// Version 7 layout (without timestamp).
+    #[value(name = "7")]
+    V7,
+}
+
+impl From<UuidVersionArg> for UuidVersion {
+    fn from(version: UuidVersionArg) -> Self {
+        match version {
+            UuidVersionArg::V4 => UuidVersion::V4,
+            UuidVersionArg::V5 => UuidVersion::V5,
+            UuidVersionArg::V7 => UuidVersion::V7,
+        }
+    }
+}
+
+/// Writes the provided key either raw or using the provided encoding.
+///
+/// The encoded key is wiped from memory after writing it.
+fn print_key(out: &mut dyn Write, key: &[u8], encoding: Encoding, raw: bool) -> io::Result<()> {
+    if raw {
+        out.write_all(key)?;
+    } else {
+        let encoded = Zeroizing::new(encoding.encode(key));
+        writeln!(out, "{}", encoded.as_str())?;
+    }
+    out.flush()
+}
+
+/// Creates the output file for keys with permissions `0600` failing if it exists.
+fn create_output(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+/// Opens the output for keys, i.e., the given file or stdout.
+fn open_output(path: Option<&Path>) -> Result<Box<dyn Write>, CliError> {
+    match path {
+        Some(path) => {
+            let file = create_output(path)
+                .map_err(|error| CliError::OutputFile(path.to_owned(), error))?;
+            Ok(Box::new(file))
         }
-        Command::Hex { bytes, info } => {
-            let deriver = builder.build().unwrap();
+        None => Ok(Box::new(io::stdout().lock())),
+    }
+}
+
+/// Checks up front whether a key of the given size can be derived.
+fn check_key_size(bytes: usize) -> Result<(), CliError> {
+    if bytes > MAX_KEY_LEN {
+        return Err(CliError::KeySize(bytes));
+    }
+    Ok(())
+}
 
-            let mut out = vec![0u8; bytes as usize];
-            deriver.derive_key(&info, &mut out).unwrap();
+/// Derives a key of the given size.
+///
+/// With an `index`, the info material and the index are framed as segments (see
+/// [`Deriver::derive_key_multi`]). The key is wiped from memory when it is dropped.
+fn derive_key(
+    deriver: &Deriver,
+    info: &[u8],
+    index: Option<u32>,
+    bytes: usize,
+    group_only: bool,
+) -> Result<Zeroizing<Vec<u8>>, CliError> {
+    let mut key = Zeroizing::new(vec![0; bytes]);
+    let result = match index {
+        Some(index) => {
+            let segments = [info, &index.to_be_bytes()];
+            if group_only {
+                deriver.derive_group_key_multi(&segments, &mut key)
+            } else {
+                deriver.derive_key_multi(&segments, &mut key)
+            }
+        }
+        None if group_only => deriver.derive_group_key(info, &mut key),
+        None => deriver.derive_key(info, &mut key),
+    };
+    result.map_err(|_| CliError::KeySize(bytes))?;
+    Ok(key)
+}
 
-            let mut formatted = String::with_capacity(2 * out.len());
-            for byte in &out {
-                write!(formatted, "{:02x}", byte).unwrap();
+/// Derives keys of the given size and writes them to the output.
+///
+/// With a `count`, the given number of keys is derived from the info material and the
+/// respective index (see [`derive_key`]).
+#[allow(clippy::too_many_arguments)]
+fn derive_and_print(
+    out: &mut dyn Write,
+    deriver: &Deriver,
+    info: &[u8],
+    count: Option<u32>,
+    bytes: usize,
+    group_only: bool,
+    encoding: Encoding,
+    raw: bool,
+) -> Result<(), CliError> {
+    match count {
+        Some(count) => {
+            for index in 0..count {
+                let key = derive_key(deriver, info, Some(index), bytes, group_only)?;
+                print_key(out, &key, encoding, raw).map_err(CliError::Output)?;
             }
+        }
+        None => {
+            let key = derive_key(deriver, info, None, bytes, group_only)?;
+            print_key(out, &key, encoding, raw).map_err(CliError::Output)?;
+        }
+    }
+    Ok(())
+}
+
+/// The environment variable for providing a fake device secret.
+const FAKE_SECRET_VAR: &str = "FAKE_RPI_DERIVE_KEY_SECRET";
+
+/// The default size of the keys derived with the `derive` subcommand in bytes.
+const DERIVE_KEY_SIZE: usize = 32;
 
-            println!("{}", formatted);
+/// Errors reported by the CLI.
+#[derive(Debug, Error)]
+enum CliError {
+    #[error("Unable to read the status of the device secret. {0}")]
+    Status(io::Error),
+    #[error("Device secret has not been initialized. Run `rpi-derive-key init` first.")]
+    Uninitialized,
+    #[error("Aborted. Pass `--yes` to confirm writing the device secret.")]
+    Unconfirmed,
+    #[error("Unable to build key deriver. {0}")]
+    Build(BuildError),
+    #[error("Unable to read the info material. {0}")]
+    Info(io::Error),
+    #[error(
+        "Unable to derive a key of {0} bytes. At most {} bytes can be derived.",
+        MAX_KEY_LEN
+    )]
+    KeySize(usize),
+    #[error("Unable to write the key. {0}")]
+    Output(io::Error),
+    #[error("Unable to create the output file {0:?}. {1}")]
+    OutputFile(PathBuf, io::Error),
+    #[error("The device secret has no group secret (its upper 128-bits are zero).")]
+    NoGroupSecret,
+}
+
+impl From<BuildError> for CliError {
+    fn from(error: BuildError) -> Self {
+        match error {
+            BuildError::Uninitialized => CliError::Uninitialized,
+            error => CliError::Build(error),
         }
-        Command::Uuid { info } => {
-            let deriver = builder.build().unwrap();
+    }
+}
+
+/// Parses a fake device secret given as 64 hex characters (32 bytes).
+fn parse_fake_secret(value: &str) -> Result<[u8; 32], String> {
+    if value.len() != 64 {
+        return Err(format!(
+            "Expected 64 hex characters (32 bytes) but got {}.",
+            value.len()
+        ));
+    }
+    let mut secret = [0; 32];
+    data_encoding::HEXLOWER_PERMISSIVE
+        .decode_mut(value.as_bytes(), &mut secret)
+        .map_err(|error| format!("Expected hex characters. {}.", error.error))?;
+    Ok(secret)
+}
+
+/// The human-readable name of the provided secret location.
+fn location_name(location: SecretLocation) -> &'static str {
+    match location {
+        SecretLocation::PrivateKey => "private key",
+        SecretLocation::CustomerOtp => "customer OTP",
+        SecretLocation::Auto => "auto-detected",
+    }
+}
+
+/// The status of the device secret printed by `status` and `init`.
+#[derive(Debug, Clone, Copy)]
+struct StatusReport {
+    /// Indicates whether the customer OTP registers have been initialized.
+    has_customer_otp: bool,
+    /// Indicates whether the private key OTP registers have been initialized.
+    has_private_key: bool,
+    /// The location of the active device secret, if any.
+    secret_location: Option<SecretLocation>,
+    /// Indicates whether the private key and customer OTP store different secrets.
+    secrets_differ: bool,
+}
+
+impl StatusReport {
+    /// Reads the status of the device secret at the provided location, if any.
+    ///
+    /// With a fake device secret, no hardware is accessed and only the location of the
+    /// fake secret is reported as initialized.
+    fn read(location: Option<SecretLocation>, fake: bool) -> Result<Self, CliError> {
+        if let (true, Some(location)) = (fake, location) {
+            return Ok(Self {
+                has_customer_otp: location == SecretLocation::CustomerOtp,
+                has_private_key: location == SecretLocation::PrivateKey,
+                secret_location: Some(location),
+                secrets_differ: false,
+            });
+        }
+        let status = rpi_derive_key::status().map_err(CliError::Status)?;
+        Ok(Self {
+            has_customer_otp: status.has_customer_otp,
+            has_private_key: status.has_private_key,
+            secret_location: location,
+            secrets_differ: secrets_differ(),
+        })
+    }
+}
+
+/// Prints the provided status either human-readable or as JSON.
+fn print_status(status: &StatusReport, json: bool) {
+    if json {
+        let output = serde_json::json!({
+            "has_customer_otp": status.has_customer_otp,
+            "has_private_key": status.has_private_key,
+            "secret_location": status.secret_location,
+            "secrets_differ": status.secrets_differ,
+        });
+        println!("{}", output);
+    } else {
+        println!("Has Customer OTP: {}", status.has_customer_otp);
+        println!("Has Private Key: {}", status.has_private_key);
+        println!(
+            "Secret Location: {}",
+            status.secret_location.map(location_name).unwrap_or("none")
+        );
+        if status.secrets_differ {
+            println!("WARNING: private key and customer OTP differ");
+        }
+    }
+}
 
-            let mut out = [0; 16];
-            deriver.derive_key(&info, &mut out).unwrap();
-            let id = uuid::Builder::from_random_bytes(out).into_uuid();
+/// Checks whether the private key and customer OTP registers store different secrets.
+///
+/// Errors are ignored as the diagnosis is merely informative.
+fn secrets_differ() -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        rpi_derive_key::diagnose()
+            .map(|diagnosis| diagnosis.secrets_differ)
+            .unwrap_or(false)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        false
+    }
+}
+
+fn main() {
+    if let Err(error) = run(Args::parse()) {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
+}
+
+/// Runs the CLI with the provided arguments.
+fn run(args: Args) -> Result<(), CliError> {
+    let mut builder = DeriverBuilder::new()
+        .with_salt(args.salt)
+        .with_use_customer_otp(args.customer_otp);
+    if let Some(salt) = &args.salt_hex {
+        builder.set_salt_hex(salt)?;
+    }
+    if let Some(secret) = &args.fake_secret {
+        eprintln!("Warning! Using fake secret.");
+        builder.set_fake_secret(secret);
+    }
+    let fake = args.fake_secret.is_some() || std::env::var_os(FAKE_SECRET_VAR).is_some();
+
+    match args.cmd {
+        Command::Status => {
+            // Building without initialization never writes to the OTP.
+            let location = builder
+                .build()
+                .ok()
+                .map(|deriver| deriver.secret_location());
+            print_status(&StatusReport::read(location, fake)?, args.json);
+        }
+        Command::Init {
+            location,
+            yes,
+            group_secret,
+        } => {
+            let mut builder = builder.initialize(true);
+            if let Some(location) = location {
+                builder.set_secret_location(location.into());
+            }
+            if let Some(group_secret) = group_secret {
+                builder.set_group_secret(group_secret.as_bytes());
+            }
+            match builder.build_plan() {
+                Plan::UseExisting => {
+                    eprintln!("The device secret has already been initialized.");
+                }
+                Plan::WouldInitialize(location) => {
+                    eprintln!(
+                        "A new device secret will be irreversibly written to the {} OTP \
+                         registers.",
+                        location_name(location)
+                    );
+                    if !yes {
+                        return Err(CliError::Unconfirmed);
+                    }
+                }
+                Plan::WouldFail(error) => return Err(error.into()),
+            }
+            let deriver = builder.build()?;
+            let status = StatusReport::read(Some(deriver.secret_location()), fake)?;
+            print_status(&status, args.json);
+        }
+        Command::Hex {
+            bytes,
+            encoding,
+            raw,
+            count,
+            output,
+            info,
+        } => {
+            check_key_size(bytes as usize)?;
+            let deriver = builder.build()?;
+            let info = info.read().map_err(CliError::Info)?;
+            let mut out = open_output(output.as_deref())?;
+            derive_and_print(
+                &mut out,
+                &deriver,
+                &info,
+                count,
+                bytes as usize,
+                false,
+                encoding,
+                raw,
+            )?;
+        }
+        Command::Group {
+            bytes,
+            encoding,
+            info,
+        } => {
+            check_key_size(bytes)?;
+            let deriver = builder.build()?;
+            if !deriver.has_group_secret() {
+                return Err(CliError::NoGroupSecret);
+            }
+            let info = info.read().map_err(CliError::Info)?;
+            derive_and_print(
+                &mut io::stdout().lock(),
+                &deriver,
+                &info,
+                None,
+                bytes,
+                true,
+                encoding,
+                false,
+            )?;
+        }
+        Command::Uuid { version, info } => {
+            let deriver = builder.build()?;
+            let info = info.read().map_err(CliError::Info)?;
+            let id = deriver
+                .derive_uuid_with_version(&info, version.into())
+                .map_err(|_| CliError::KeySize(16))?;
             println!("{}", id);
         }
-        Command::Check => todo!(),
-        Command::Derive { .. } => todo!(),
+        Command::ExportPublic { encoding, info } => {
+            let deriver = builder.build()?;
+            let info = info.read().map_err(CliError::Info)?;
+            let public_key = deriver
+                .derive_ed25519_public(&info)
+                .map_err(|_| CliError::KeySize(32))?;
+            print_key(&mut io::stdout().lock(), &public_key, encoding, false)
+                .map_err(CliError::Output)?;
+        }
+        Command::Check => {
+            // Building without initialization never writes to the OTP.
+            let deriver = builder.build()?;
+            println!(
+                "Secret Location: {}",
+                location_name(deriver.secret_location())
+            );
+        }
+        Command::Derive {
+            group_only,
+            bytes,
+            encoding,
+            raw,
+            count,
+            output,
+            info,
+        } => {
+            check_key_size(bytes)?;
+            let deriver = builder.build()?;
+            if group_only && !deriver.has_group_secret() {
+                return Err(CliError::NoGroupSecret);
+            }
+            let info = info.read().map_err(CliError::Info)?;
+            let mut out = open_output(output.as_deref())?;
+            derive_and_print(
+                &mut out, &deriver, &info, count, bytes, group_only, encoding, raw,
+            )?;
+        }
     }
+    Ok(())
 }
@@ -0,0 +1,386 @@
+//! End-to-end tests of the command line interface using a fake device secret.
+//!
+//! The outputs for the fake device secret are fixed such that any change of the derived
+//! keys or the reference UX is noticed.
+
+use assert_cmd::Command;
+
+/// The fake device secret used for testing.
+const FAKE_SECRET: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+/// The key derived from the fake device secret for the info material `info`.
+///
+/// This is HKDF-SHA3-512 as specified in RFC 5869 without salt.
+const INFO_KEY: &str = "7db694059abb72e514d193fed3a59be5128ff5729a0d15d5404fe1b157a4e979";
+
+/// Creates a command running `rpi-derive-key` with the fake device secret.
+fn cmd() -> Command {
+    let mut cmd = Command::cargo_bin("rpi-derive-key").expect("Unable to find `rpi-derive-key`.");
+    cmd.env("FAKE_RPI_DERIVE_KEY_SECRET", FAKE_SECRET);
+    cmd
+}
+
+/// Runs `rpi-derive-key` with the provided arguments and returns its standard output.
+fn run(args: &[&str]) -> String {
+    run_with_stdin(args, &[])
+}
+
+/// Runs `rpi-derive-key` with the provided arguments and standard input and returns its
+/// standard output.
+fn run_with_stdin(args: &[&str], stdin: &[u8]) -> String {
+    String::from_utf8(run_raw(args, stdin)).expect("Output must be valid UTF-8.")
+}
+
+/// Runs `rpi-derive-key` with the provided arguments and standard input and returns its
+/// raw standard output.
+fn run_raw(args: &[&str], stdin: &[u8]) -> Vec<u8> {
+    let assert = cmd().args(args).write_stdin(stdin).assert().success();
+    assert.get_output().stdout.clone()
+}
+
+/// Runs `rpi-derive-key` with the provided arguments expecting it to fail and returns
+/// its standard error.
+fn run_failing(args: &[&str]) -> String {
+    let assert = cmd().args(args).assert().failure();
+    String::from_utf8(assert.get_output().stderr.clone()).expect("Output must be valid UTF-8.")
+}
+
+/// Tests that different salts produce different keys.
+#[test]
+fn test_salt_changes_keys() {
+    for cmd in [&["derive"][..], &["hex", "32"], &["uuid"]] {
+        let unsalted = run(&[cmd, &["info"]].concat());
+        let salted_a = run(&[cmd, &["--salt", "a", "info"]].concat());
+        let salted_b = run(&[cmd, &["--salt", "b", "info"]].concat());
+        assert_ne!(unsalted, salted_a);
+        assert_ne!(unsalted, salted_b);
+        assert_ne!(salted_a, salted_b);
+        assert_eq!(salted_a, run(&[cmd, &["--salt", "a", "info"]].concat()));
+    }
+}
+
+/// Tests that the info material can be read from stdin and from a file.
+#[test]
+fn test_info_sources() {
+    let path = std::env::temp_dir().join(format!("rpi-derive-key-info-{}", std::process::id()));
+    std::fs::write(&path, "info").unwrap();
+    for cmd in [&["derive"][..], &["hex", "32"], &["uuid"]] {
+        let expected = run(&[cmd, &["info"]].concat());
+        assert_eq!(
+            run_with_stdin(&[cmd, &["--info-stdin"]].concat(), b"info"),
+            expected
+        );
+        assert_eq!(
+            run(&[cmd, &["--info-file", path.to_str().unwrap()]].concat()),
+            expected
+        );
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Tests the different encodings of a known derivation.
+#[test]
+fn test_encodings() {
+    let expected = [
+        ("hex", INFO_KEY),
+        ("base64", "fbaUBZq7cuUU0ZP+06Wb5RKP9XKaDRXVQE/hsVek6Xk="),
+        ("base64url", "fbaUBZq7cuUU0ZP-06Wb5RKP9XKaDRXVQE_hsVek6Xk"),
+        (
+            "base32",
+            "PW3JIBM2XNZOKFGRSP7NHJM34UJI75LSTIGRLVKAJ7Q3CV5E5F4Q====",
+        ),
+    ];
+    for (encoding, key) in expected {
+        for cmd in [&["derive"][..], &["hex", "32"]] {
+            let output = run(&[cmd, &["--encoding", encoding, "info"]].concat());
+            assert_eq!(output.trim_end(), key);
+        }
+    }
+    assert_eq!(run(&["derive", "info"]), run(&["hex", "32", "info"]));
+    assert_eq!(
+        run(&["derive", "info"]),
+        run(&["derive", "--encoding", "hex", "info"])
+    );
+}
+
+/// Tests the different UUID versions.
+#[test]
+fn test_uuid_versions() {
+    let default = run(&["uuid", "info"]);
+    assert_eq!(run(&["uuid", "--version", "4", "info"]), default);
+    for version in ["4", "5", "7"] {
+        let id = run(&["uuid", "--version", version, "info"]);
+        assert_eq!(&id[14..15], version);
+    }
+}
+
+/// Tests that `--raw` writes the raw bytes of the key.
+#[test]
+fn test_raw_output() {
+    for cmd in [&["derive"][..], &["hex", "32"]] {
+        let output = run_raw(&[cmd, &["--raw", "info"]].concat(), &[]);
+        assert_eq!(data_encoding::HEXLOWER.encode(&output), INFO_KEY);
+    }
+}
+
+/// Tests the size of keys derived with the `derive` subcommand.
+#[test]
+fn test_derive_bytes() {
+    assert_eq!(
+        run(&["derive", "info"]),
+        run(&["derive", "--bytes", "32", "info"])
+    );
+    assert_eq!(
+        run(&["derive", "--bytes", "64", "info"]),
+        run(&["hex", "64", "info"])
+    );
+    assert_eq!(
+        run(&["derive", "--group-only", "--bytes", "16", "info"])
+            .trim_end()
+            .len(),
+        32
+    );
+    let error = run_failing(&["derive", "--bytes", "16321", "info"]);
+    assert!(error.contains("At most 16320 bytes can be derived."));
+}
+
+/// Tests that errors are reported without panicking.
+#[test]
+fn test_error_reporting() {
+    let error = run_failing(&["derive", "--info-file", "/nonexistent/info"]);
+    assert!(error.contains("Unable to read the info material."));
+    assert!(!error.contains("panicked"));
+}
+
+/// Tests that `check` reports the location of the device secret.
+#[test]
+fn test_check_location() {
+    assert_eq!(run(&["check"]), "Secret Location: private key\n");
+    assert_eq!(
+        run(&["--customer-otp", "check"]),
+        "Secret Location: customer OTP\n"
+    );
+}
+
+/// Tests deriving multiple indexed keys with `--count`.
+#[test]
+fn test_count() {
+    let keys = run(&["derive", "--count", "3", "disk"]);
+    let keys = keys.lines().collect::<Vec<_>>();
+    assert_eq!(keys.len(), 3);
+    assert!(keys.iter().all(|key| key.len() == 64));
+    assert_ne!(keys[0], keys[1]);
+    assert_eq!(
+        run(&["hex", "32", "--count", "3", "disk"]),
+        run(&["derive", "--count", "3", "disk"])
+    );
+    // The info material and the index are framed with their lengths.
+    let info = b"\x00\x00\x00\x04disk\x00\x00\x00\x04\x00\x00\x00\x02";
+    assert_eq!(
+        run_with_stdin(&["derive", "--info-stdin"], info).trim_end(),
+        keys[2]
+    );
+    assert_ne!(
+        run_with_stdin(&["derive", "--info-stdin"], b"disk\x00\x00\x00\x02").trim_end(),
+        keys[2]
+    );
+    assert_eq!(run(&["derive", "--count", "0", "disk"]), "");
+}
+
+/// Tests that `export-public` prints only the public key of the derived Ed25519 key.
+#[test]
+fn test_export_public() {
+    let public_key = run(&["export-public", "enrollment"]);
+    assert_eq!(public_key.trim_end().len(), 64);
+    assert_ne!(public_key, run(&["derive", "enrollment"]));
+    assert_ne!(public_key, run(&["export-public", "other"]));
+    let base64 = run(&["export-public", "--encoding", "base64", "enrollment"]);
+    assert_eq!(base64.trim_end().len(), 44);
+}
+
+/// Tests providing the salt hex-encoded with `--salt-hex`.
+#[test]
+fn test_salt_hex() {
+    assert_eq!(
+        run(&["derive", "--salt-hex", "73616c74", "info"]),
+        run(&["derive", "--salt", "salt", "info"])
+    );
+    assert_ne!(
+        run(&["derive", "--salt-hex", "ff00", "info"]),
+        run(&["derive", "info"])
+    );
+    let error = run_failing(&["derive", "--salt-hex", "xyz", "info"]);
+    assert!(error.contains("Invalid hex-encoded salt."));
+    run_failing(&["derive", "--salt", "a", "--salt-hex", "61", "info"]);
+}
+
+/// Tests deriving group keys with `group`.
+#[test]
+fn test_group() {
+    assert_eq!(
+        run(&["group", "shared"]),
+        run(&["derive", "--group-only", "shared"])
+    );
+    assert_eq!(
+        run(&["group", "--bytes", "16", "shared"]),
+        run(&["derive", "--group-only", "--bytes", "16", "shared"])
+    );
+    assert_ne!(run(&["group", "shared"]), run(&["derive", "shared"]));
+    // A device secret without group secret has zero upper 128-bits.
+    let secret = format!("{}{}", "00".repeat(16), &FAKE_SECRET[32..]);
+    for cmd in [&["group"][..], &["derive", "--group-only"]] {
+        let error = run_failing(&[&["--fake-secret", &secret], cmd, &["shared"]].concat());
+        assert!(error.contains("The device secret has no group secret"));
+    }
+}
+
+/// Tests writing keys to a file with `--output`.
+#[test]
+fn test_output() {
+    let dir = std::env::temp_dir().join(format!("rpi-derive-key-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("key");
+    let path_str = path.to_str().unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(run(&["derive", "--output", path_str, "info"]), "");
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        run(&["derive", "info"])
+    );
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    // Existing files are never overwritten.
+    let error = run_failing(&["hex", "32", "--raw", "--output", path_str, "info"]);
+    assert!(error.contains("Unable to create the output file"));
+    std::fs::remove_file(&path).unwrap();
+    run(&["hex", "32", "--raw", "--output", path_str, "info"]);
+    assert_eq!(
+        std::fs::read(&path).unwrap(),
+        run_raw(&["hex", "32", "--raw", "info"], &[])
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Tests providing the fake device secret with `--fake-secret` instead of the
+/// environment.
+#[test]
+fn test_fake_secret_flag() {
+    let assert = cmd()
+        .env_remove("FAKE_RPI_DERIVE_KEY_SECRET")
+        .args(["--fake-secret", FAKE_SECRET, "hex", "32", "info"])
+        .assert()
+        .success()
+        .stderr("Warning! Using fake secret.\n");
+    assert_eq!(
+        String::from_utf8(assert.get_output().stdout.clone()).unwrap(),
+        run(&["hex", "32", "info"])
+    );
+    let other = "ff".repeat(32);
+    assert_ne!(
+        run(&["derive", "--fake-secret", &other, "info"]),
+        run(&["derive", "info"])
+    );
+    let error = run_failing(&["derive", "--fake-secret", "00", "info"]);
+    assert!(error.contains("Expected 64 hex characters (32 bytes) but got 2."));
+    let error = run_failing(&["derive", "--fake-secret", &"xy".repeat(32), "info"]);
+    assert!(error.contains("Expected hex characters."));
+}
+
+/// Tests that the hex and UUID outputs are stable.
+#[test]
+fn test_stable_outputs() {
+    assert_eq!(run(&["hex", "32", "info"]), format!("{}\n", INFO_KEY));
+    assert_eq!(run(&["derive", "info"]), format!("{}\n", INFO_KEY));
+    assert_eq!(
+        run(&["hex", "16", "--salt", "abc", "info"]),
+        "d7aa581afda77b58790dcb21e6b91a9c\n"
+    );
+    assert_eq!(
+        run(&["uuid", "info"]),
+        "7db69405-9abb-42e5-94d1-93fed3a59be5\n"
+    );
+    assert_eq!(
+        run(&["group", "info"]),
+        "72b00fc9bf705db105089a372281a44a32d3ff3536b0e7fc57d1d375d5d1d5fe\n"
+    );
+    assert_eq!(
+        run(&["export-public", "info"]),
+        "dfef963705e49881144aa89ff749e2e6a5558ec654c2e84fad854cd74a8dad1b\n"
+    );
+    cmd()
+        .args(["hex", "32", "info"])
+        .assert()
+        .success()
+        .stderr("Warning! Using fake secret.\n");
+}
+
+/// Tests that `status` prints the expected lines without accessing any hardware.
+#[test]
+fn test_status() {
+    assert_eq!(
+        run(&["status"]),
+        "Has Customer OTP: false\nHas Private Key: true\nSecret Location: private key\n"
+    );
+    assert_eq!(
+        run(&["--customer-otp", "status"]),
+        "Has Customer OTP: true\nHas Private Key: false\nSecret Location: customer OTP\n"
+    );
+    assert_eq!(
+        run(&["status", "--json"]),
+        "{\"has_customer_otp\":false,\"has_private_key\":true,\"secret_location\":\
+         \"private_key\",\"secrets_differ\":false}\n"
+    );
+}
+
+/// Tests that `init` never writes a fake device secret.
+#[test]
+fn test_init() {
+    let output = cmd().arg("init").assert().success().get_output().clone();
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("The device secret has already been initialized."));
+    assert_eq!(run(&["check"]), "Secret Location: private key\n");
+}
+
+/// Tests that no subcommand panics, neither when succeeding nor when failing.
+#[test]
+fn test_no_panics() {
+    let invocations: &[&[&str]] = &[
+        &["status"],
+        &["status", "--json"],
+        &["check"],
+        &["init"],
+        &["init", "--yes", "--location", "customer-otp"],
+        &["derive", "info"],
+        &["derive", "--bytes", "16321", "info"],
+        &["group", "info"],
+        &["hex", "32", "info"],
+        &["hex", "0", "info"],
+        &["uuid", "--version", "7", "info"],
+        &["export-public", "info"],
+        &["--salt-hex", "xyz", "derive", "info"],
+        &["derive", "--info-file", "/nonexistent/info"],
+    ];
+    for args in invocations {
+        let output = cmd().args(*args).output().unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(!stderr.contains("panicked"), "{:?}: {}", args, stderr);
+        assert_ne!(output.status.code(), Some(101), "{:?}: {}", args, stderr);
+    }
+    for subcommand in [
+        "status",
+        "check",
+        "init",
+        "derive",
+        "group",
+        "hex",
+        "export-public",
+        "uuid",
+    ] {
+        cmd().args([subcommand, "--help"]).assert().success();
+    }
+}
@@ -0,0 +1,144 @@
+//! The [`DeriverCache`] for memoizing derived keys (available without `std`).
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use zeroize::Zeroizing;
+
+use crate::{Deriver, InvalidLength};
+
+/// A cached key together with the info material and length it has been derived with.
+struct CacheEntry {
+    /// The info material used to derive the key.
+    info: Zeroizing<Vec<u8>>,
+    /// The derived key.
+    key: Zeroizing<Vec<u8>>,
+}
+
+/// A cache for keys derived with a [`Deriver`].
+///
+/// Keys are cached by their info material and length. The cache holds at most
+/// [`capacity`][Self::capacity] keys and evicts the least recently used key when full.
+/// Evicted keys are wiped from memory as are all keys when the cache is dropped.
+///
+/// Note that, unlike the device secret, the cached keys are not stored in locked memory
+/// and may thus be swapped to disk.
+pub struct DeriverCache {
+    /// The deriver used for deriving keys.
+    deriver: Deriver,
+    /// The maximal number of cached keys.
+    capacity: usize,
+    /// The cached keys ordered from least to most recently used.
+    entries: Vec<CacheEntry>,
+}
+
+impl DeriverCache {
+    /// Creates a cache holding at most `capacity` keys derived with the given deriver.
+    ///
+    /// The capacity is at least one. Memory for the entries is allocated as keys are
+    /// cached, hence, the capacity may be arbitrarily large.
+    pub fn new(deriver: Deriver, capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            deriver,
+            capacity,
+            entries: Vec::new(),
+        }
+    }
+
+    /// The deriver used for deriving keys.
+    pub fn deriver(&self) -> &Deriver {
+        &self.deriver
+    }
+
+    /// The maximal number of cached keys.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of currently cached keys.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Indicates whether no keys are cached.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the cached device-specific key for the given info material and length or
+    /// derives and caches it.
+    pub fn get_or_derive<I: AsRef<[u8]>>(
+        &mut self,
+        info: I,
+        len: usize,
+    ) -> Result<&[u8], InvalidLength> {
+        let info = info.as_ref();
+        let position = self
+            .entries
+            .iter()
+            .position(|entry| entry.key.len() == len && entry.info.as_slice() == info);
+        let entry = match position {
+            Some(position) => self.entries.remove(position),
+            None => {
+                let key = Zeroizing::new(self.deriver.derive_key_vec(info, len)?);
+                if self.entries.len() == self.capacity {
+                    // Dropping the entry wipes the key.
+                    self.entries.remove(0);
+                }
+                CacheEntry {
+                    info: Zeroizing::new(info.to_vec()),
+                    key,
+                }
+            }
+        };
+        self.entries.push(entry);
+        Ok(&self.entries[self.entries.len() - 1].key)
+    }
+
+    /// Removes and wipes all cached keys.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+impl fmt::Debug for DeriverCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeriverCache")
+            .field("deriver", &self.deriver)
+            .field("capacity", &self.capacity)
+            .field("len", &self.entries.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::tests::FAKE_SECRET;
+
+    /// Tests caching of derived keys.
+    #[test]
+    fn test_deriver_cache() {
+        let deriver = Deriver::new_fake(None, &FAKE_SECRET);
+        let mut cache = DeriverCache::new(deriver.clone(), 2);
+        assert!(cache.is_empty());
+        let a = cache.get_or_derive("a", 32).unwrap().to_vec();
+        assert_eq!(a, deriver.derive_key_vec("a", 32).unwrap());
+        assert_eq!(cache.get_or_derive("a", 16).unwrap(), &a[..16]);
+        assert_eq!(cache.len(), 2);
+        // Using `a` with 32 bytes makes `a` with 16 bytes the least recently used key.
+        assert_eq!(cache.get_or_derive("a", 32).unwrap(), a);
+        cache.get_or_derive("b", 32).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get_or_derive("a", 32).unwrap(), a);
+        assert!(cache.get_or_derive("c", 16321).is_err());
+        assert_eq!(cache.len(), 2);
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(DeriverCache::new(deriver.clone(), 0).capacity(), 1);
+        let mut unbounded = DeriverCache::new(deriver, usize::MAX);
+        assert_eq!(unbounded.capacity(), usize::MAX);
+        assert_eq!(unbounded.get_or_derive("a", 32).unwrap(), a);
+    }
+}
@@ -0,0 +1,228 @@
+//! Dispatching of HKDF to the supported hash algorithms.
+//!
+//! HKDF is implemented on top of HMAC (see RFC 5869) such that the output key material
+//! can be produced incrementally.
+
+use hmac::{
+    digest::{KeyInit, Output, OutputSizeUser},
+    Hmac, Mac,
+};
+use zeroize::Zeroize;
+
+use crate::HashAlgorithm;
+
+/// An HKDF instance based on one of the supported hash algorithms.
+///
+/// Each variant holds an HMAC instance keyed with the pseudorandom key.
+#[derive(Clone)]
+#[allow(clippy::large_enum_variant)] // The largest variant is the default one.
+pub(crate) enum Hkdf {
+    /// HKDF based on SHA3-512.
+    Sha3_512(Hmac<sha3::Sha3_512>),
+    /// HKDF based on SHA-256.
+    Sha256(Hmac<sha2::Sha256>),
+    /// HKDF based on SHA-512.
+    Sha512(Hmac<sha2::Sha512>),
+}
+
+/// Error produced by [`Hkdf::expand_with`].
+#[derive(Debug)]
+pub(crate) enum ExpandError<E> {
+    /// The length of the output key material is too long.
+    InvalidLength(hkdf::InvalidLength),
+    /// Error produced by the callback.
+    Callback(E),
+}
+
+impl Hkdf {
+    /// Performs the HKDF extract step with the provided salt and input key material.
+    pub fn new(algorithm: HashAlgorithm, salt: Option<&[u8]>, ikm: &[u8]) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha3_512 => Self::Sha3_512(extract(salt, ikm)),
+            HashAlgorithm::Sha256 => Self::Sha256(extract(salt, ikm)),
+            HashAlgorithm::Sha512 => Self::Sha512(extract(salt, ikm)),
+        }
+    }
+
+    /// The hash algorithm of this HKDF instance.
+    pub fn algorithm(&self) -> HashAlgorithm {
+        match self {
+            Self::Sha3_512(_) => HashAlgorithm::Sha3_512,
+            Self::Sha256(_) => HashAlgorithm::Sha256,
+            Self::Sha512(_) => HashAlgorithm::Sha512,
+        }
+    }
+
+    /// Performs the HKDF expand step with the provided info material.
+    ///
+    /// The info material is given as parts which are concatenated.
+    pub fn expand(&self, info: &[&[u8]], okm: &mut [u8]) -> Result<(), hkdf::InvalidLength> {
+        let mut offset = 0;
+        self.expand_with(info, okm.len(), |block| {
+            okm[offset..offset + block.len()].copy_from_slice(block);
+            offset += block.len();
+            Ok::<_, ()>(())
+        })
+        .map_err(|error| {
+            match error {
+                ExpandError::InvalidLength(error) => error,
+                ExpandError::Callback(()) => unreachable!("The callback never fails."),
+            }
+        })
+    }
+
+    /// Performs the HKDF expand step with the provided info material producing `len`
+    /// bytes of output key material incrementally.
+    ///
+    /// The callback is invoked with consecutive blocks of output key material.
+    pub fn expand_with<E>(
+        &self,
+        info: &[&[u8]],
+        len: usize,
+        callback: impl FnMut(&[u8]) -> Result<(), E>,
+    ) -> Result<(), ExpandError<E>> {
+        match self {
+            Self::Sha3_512(hmac) => expand(hmac, info, len, callback),
+            Self::Sha256(hmac) => expand(hmac, info, len, callback),
+            Self::Sha512(hmac) => expand(hmac, info, len, callback),
+        }
+    }
+}
+
+/// Performs the HKDF extract step and keys an HMAC instance with the pseudorandom key.
+fn extract<M: Mac + KeyInit>(salt: Option<&[u8]>, ikm: &[u8]) -> M {
+    // An absent salt is equivalent to a string of zeros of the hash's output size
+    // as HMAC pads keys with zeros.
+    let mut extractor =
+        <M as Mac>::new_from_slice(salt.unwrap_or_default()).expect("HMAC accepts any key.");
+    extractor.update(ikm);
+    let mut prk = extractor.finalize().into_bytes();
+    let hmac = <M as Mac>::new_from_slice(&prk).expect("HMAC accepts any key.");
+    prk.as_mut_slice().zeroize();
+    hmac
+}
+
+/// Performs the HKDF expand step with the HMAC instance keyed with the pseudorandom key.
+fn expand<M: Mac + Clone, E>(
+    hmac: &M,
+    info: &[&[u8]],
+    len: usize,
+    mut callback: impl FnMut(&[u8]) -> Result<(), E>,
+) -> Result<(), ExpandError<E>> {
+    if len > 255 * <M as OutputSizeUser>::output_size() {
+        return Err(ExpandError::InvalidLength(hkdf::InvalidLength));
+    }
+    let mut block = Output::<M>::default();
+    let mut remaining = len;
+    let mut counter = 1u8;
+    let mut result = Ok(());
+    while remaining > 0 {
+        let mut mac = hmac.clone();
+        if counter > 1 {
+            mac.update(&block);
+        }
+        for part in info {
+            mac.update(part);
+        }
+        mac.update(&[counter]);
+        block = mac.finalize().into_bytes();
+        let size = remaining.min(block.len());
+        if let Err(error) = callback(&block[..size]) {
+            result = Err(ExpandError::Callback(error));
+            break;
+        }
+        remaining -= size;
+        counter = counter.wrapping_add(1);
+    }
+    block.as_mut_slice().zeroize();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    /// Tests that the output matches the output of the `hkdf` crate.
+    #[test]
+    pub fn test_hkdf_compatibility() {
+        fn reference(algorithm: HashAlgorithm, salt: Option<&[u8]>, okm: &mut [u8]) {
+            let ikm = b"input key material";
+            let result = match algorithm {
+                HashAlgorithm::Sha3_512 => {
+                    hkdf::Hkdf::<sha3::Sha3_512>::new(salt, ikm).expand(b"info", okm)
+                }
+                HashAlgorithm::Sha256 => {
+                    hkdf::Hkdf::<sha2::Sha256>::new(salt, ikm).expand(b"info", okm)
+                }
+                HashAlgorithm::Sha512 => {
+                    hkdf::Hkdf::<sha2::Sha512>::new(salt, ikm).expand(b"info", okm)
+                }
+            };
+            result.unwrap();
+        }
+        for algorithm in [
+            HashAlgorithm::Sha3_512,
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Sha512,
+        ] {
+            for salt in [None, Some(b"salt".as_slice())] {
+                let hkdf = Hkdf::new(algorithm, salt, b"input key material");
+                for len in [0, 1, 31, 32, 64, 65, 200, algorithm.max_key_size()] {
+                    let mut expected = vec![0; len];
+                    reference(algorithm, salt, &mut expected);
+                    let mut okm = vec![0; len];
+                    hkdf.expand(&[b"in", b"fo"], &mut okm).unwrap();
+                    assert_eq!(okm, expected);
+                }
+                let mut okm = vec![0; algorithm.max_key_size() + 1];
+                assert!(hkdf.expand(&[b"info"], &mut okm).is_err());
+            }
+        }
+    }
+
+    /// Tests the SHA-256 test vectors of RFC 5869 (Appendix A.1 to A.3), also when the
+    /// output key material is consumed block by block.
+    #[test]
+    pub fn test_rfc5869_vectors() {
+        let range = |range: core::ops::RangeInclusive<u8>| range.collect::<vec::Vec<_>>();
+        let vectors = [
+            (
+                vec![0x0b; 22],
+                range(0x00..=0x0c),
+                range(0xf0..=0xf9),
+                "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865",
+            ),
+            (
+                range(0x00..=0x4f),
+                range(0x60..=0xaf),
+                range(0xb0..=0xff),
+                "b11e398dc80327a1c8e7f78c596a49344f012eda2d4efad8a050cc4c19afa97c59045a99cac78272\
+                 71cb41c65e590e09da3275600c2f09b8367793a9aca3db71cc30c58179ec3e87c14c01d5c1f3434f\
+                 1d87",
+            ),
+            (
+                vec![0x0b; 22],
+                vec![],
+                vec![],
+                "8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d9d201395faa4b61a96c8",
+            ),
+        ];
+        for (ikm, salt, info, expected) in vectors {
+            let expected = hex::decode(expected).unwrap();
+            let hkdf = Hkdf::new(HashAlgorithm::Sha256, Some(&salt), &ikm);
+            let mut okm = vec![0; expected.len()];
+            hkdf.expand(&[&info], &mut okm).unwrap();
+            assert_eq!(okm, expected);
+            let mut blocks = vec::Vec::new();
+            hkdf.expand_with(&[&info], expected.len(), |block| {
+                blocks.push(block.to_vec());
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+            assert!(blocks.iter().all(|block| block.len() <= 32));
+            assert_eq!(blocks.concat(), expected);
+        }
+    }
+}
@@ -1,32 +1,147 @@
 //! # RPi Derive Key 🔑
 //!
 //! A utility crate for deriving secure device-specific keys on Raspberry Pi.
+//!
+//! Without the default `std` feature, only the [`Deriver`] is available. It can be
+//! created with [`Deriver::new_fake`] from a secret provided by the caller.
+#![cfg_attr(not(feature = "std"), no_std)]
 #![allow(clippy::uninlined_format_args)] // Required because MSRV = 1.65.
 
-use std::io;
+extern crate alloc;
+
+#[cfg(feature = "std")]
+use std::{io, ops::Range, path::PathBuf, time::Duration};
 
+#[cfg(feature = "aead")]
+pub use aes_gcm;
+#[cfg(feature = "aead")]
+pub use chacha20poly1305;
+#[cfg(feature = "ed25519")]
+pub use ed25519_dalek;
+#[cfg(feature = "hmac")]
+pub use hmac;
+#[cfg(feature = "custom-rng")]
+pub use rand::{CryptoRng, RngCore};
+#[cfg(all(feature = "std", not(feature = "custom-rng")))]
+use rand::{CryptoRng, RngCore};
+#[cfg(feature = "hmac")]
+pub use sha2;
+#[cfg(feature = "std")]
 use thiserror::Error;
+#[cfg(feature = "x25519")]
+pub use x25519_dalek;
+#[cfg(feature = "zeroize")]
+pub use zeroize;
 
+#[cfg(feature = "std")]
+pub use crate::deriver::DeriveError;
+#[cfg(feature = "std")]
 use crate::secrets::GroupSecret;
+#[cfg(feature = "std")]
+pub use crate::secrets::MemoryProtection;
+pub use crate::{
+    cache::DeriverCache,
+    deriver::{DeriveFromError, Deriver, InvalidLength, KdfParams},
+};
+
+/// Logs a message with the `log` crate in case the `log` feature is enabled.
+///
+/// The first argument is the level, e.g., `debug` or `warn`.
+#[cfg(all(feature = "std", target_os = "linux"))]
+macro_rules! log {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "log")]
+        ::log::$level!($($arg)+);
+    };
+}
 
+pub(crate) mod cache;
+pub(crate) mod deriver;
+pub(crate) mod kdf;
+#[cfg(feature = "std")]
 pub(crate) mod secrets;
 
-#[cfg(target_os = "linux")]
+#[cfg(all(feature = "std", target_os = "linux"))]
 pub(crate) mod rpi;
 
 /// The location where the device secret is stored.
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum SecretLocation {
     /// The device secret is stored in the private key OTP registers.
     #[default]
     PrivateKey,
     /// The device secret is stored in the customer-programmable OTP registers.
     CustomerOtp,
+    /// The location is detected automatically when building a [`Deriver`].
+    ///
+    /// The private key is used if it has been initialized. Otherwise, the
+    /// customer-programmable OTP registers are used if they have been initialized. If
+    /// neither has been initialized, the private key is used if the firmware supports
+    /// it and the customer-programmable OTP registers otherwise.
+    ///
+    /// The location actually used is available via [`Deriver::secret_location`].
+    Auto,
+}
+
+/// The hash algorithm used for the HKDF algorithm.
+///
+/// For all hash algorithms, the output is byte-for-byte compliant with HKDF as specified
+/// in RFC 5869 enabling interoperability with other implementations. Note that the info
+/// material is prefixed when using an application label, a context, or any binding.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum HashAlgorithm {
+    /// SHA3-512 (the default).
+    #[default]
+    Sha3_512,
+    /// SHA-256.
+    Sha256,
+    /// SHA-512.
+    Sha512,
+}
+
+impl HashAlgorithm {
+    /// The maximal size of keys derivable with the HKDF algorithm in bytes.
+    ///
+    /// HKDF can derive keys of at most 255 times the output size of the hash function.
+    pub const fn max_key_size(self) -> usize {
+        let output_size = match self {
+            HashAlgorithm::Sha3_512 => 64,
+            HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Sha512 => 64,
+        };
+        255 * output_size
+    }
+}
+
+/// The maximal size of keys derivable with the default hash algorithm (SHA3-512) in
+/// bytes.
+pub const MAX_KEY_LEN: usize = HashAlgorithm::Sha3_512.max_key_size();
+
+/// The version of UUIDs derived with [`Deriver::derive_uuid_with_version`].
+///
+/// Note that derived UUIDs only follow the layout of the respective version, i.e., the
+/// version and variant bits are set accordingly while all other bits are derived.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum UuidVersion {
+    /// Version 4 (random) layout (the default).
+    #[default]
+    V4,
+    /// Version 5 (SHA-1) layout.
+    V5,
+    /// Version 7 (Unix epoch time-based) layout.
+    ///
+    /// Note that derived UUIDs do not contain a timestamp.
+    V7,
 }
 
 /// Checks whether the device is a Raspberry Pi.
 ///
 /// This function simply checks whether the VCIO device `/dev/vcio` exists.
+#[cfg(feature = "std")]
 pub fn is_raspberry_pi() -> bool {
     #[cfg(target_os = "linux")]
     return rpi::vcio::Vcio::exists();
@@ -35,6 +150,7 @@ pub fn is_raspberry_pi() -> bool {
 }
 
 /// Check whether the Raspberry Pi's firmware supports storing a private key.
+#[cfg(feature = "std")]
 pub fn supports_private_key() -> bool {
     // Simply check whether the firmware support reading the private key.
     #[cfg(target_os = "linux")]
@@ -45,19 +161,314 @@ pub fn supports_private_key() -> bool {
     return true;
 }
 
+/// The oldest firmware revision supporting the private key OTP registers.
+///
+/// Firmware revisions are the times of the respective builds as UNIX timestamps. Support
+/// for the private key has been added to the firmware in February 2021.
+pub const MIN_PRIVATE_KEY_FIRMWARE_REVISION: u32 = 1_612_137_600;
+
+/// The maximal generation which can be stored in a customer OTP row (see
+/// [`DeriverBuilder::with_generation_row`]).
+#[cfg(feature = "std")]
+pub const MAX_GENERATION: u32 = u32::BITS;
+
+/// The result of checking the firmware revision (see [`verify_firmware_min_version`]).
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct FirmwareCheck {
+    /// The revision of the firmware.
+    pub revision: u32,
+    /// The oldest firmware revision supporting the private key.
+    pub min_revision: u32,
+    /// Indicates whether the firmware is recent enough to support the private key.
+    pub supports_private_key: bool,
+}
+
+/// Reads the revision of the firmware (only available on Linux).
+///
+/// The revision is the time of the firmware's build as UNIX timestamp.
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub fn firmware_revision() -> Result<u32, io::Error> {
+    let vcio = rpi::vcio::Vcio::open()?;
+    rpi::info::get_firmware_revision(&vcio)
+}
+
+/// Checks whether the firmware is recent enough to support the private key (only
+/// available on Linux).
+///
+/// In contrast to [`supports_private_key`], this does not attempt to read the private
+/// key but compares the firmware revision with [`MIN_PRIVATE_KEY_FIRMWARE_REVISION`].
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub fn verify_firmware_min_version() -> Result<FirmwareCheck, io::Error> {
+    let revision = firmware_revision()?;
+    Ok(FirmwareCheck {
+        revision,
+        min_revision: MIN_PRIVATE_KEY_FIRMWARE_REVISION,
+        supports_private_key: revision >= MIN_PRIVATE_KEY_FIRMWARE_REVISION,
+    })
+}
+
+/// Allow or disallow storing secrets in unprotected memory.
+///
+/// By default, secrets are protected from being swapped to disk (on Linux). In case the
+/// memory cannot be protected, e.g., because of a low `RLIMIT_MEMLOCK` or missing
+/// capabilities in a container, [`DeriverBuilder::build`] fails with [`BuildError::Io`].
+/// When enabled, a warning is printed instead and the secrets are stored in unprotected
+/// memory.
+///
+/// Note that this setting applies to the entire process.
+#[cfg(feature = "std")]
+pub fn set_allow_unprotected_memory(enable: bool) {
+    secrets::set_allow_unprotected(enable);
+}
+
+/// Enable or disable storing secrets in memory excluded from core dumps in case the
+/// memory cannot be protected from being swapped to disk (disabled by default).
+///
+/// On Linux, each secret is stored in a dedicated mapping of an anonymous memory file
+/// (`memfd_create`) which is excluded from core dumps with `madvise(MADV_DONTDUMP)`. In
+/// some environments, e.g., containers, `mlock` is forbidden. When enabled and the
+/// memory cannot be locked, the exclusion from core dumps suffices. While such memory
+/// may still be swapped to disk, it does not leak into core dumps of crashing
+/// processes. If the mapping cannot be created, [`set_allow_unprotected_memory`]
+/// applies. The protection actually applied is
+/// available via [`Deriver::memory_protection`].
+///
+/// Note that this setting applies to the entire process.
+#[cfg(feature = "std")]
+pub fn set_dump_exclusion_fallback(enable: bool) {
+    secrets::set_dump_exclusion_fallback(enable);
+}
+
+/// Enable or disable making the process non-dumpable while any secret is live (disabled
+/// by default, only effective on Linux).
+///
+/// The memory storing secrets is always excluded from core dumps. However, derived keys
+/// and intermediate values may still end up in core dumps. When enabled, the process is
+/// made non-dumpable with `prctl(PR_SET_DUMPABLE, 0)` while any secret is live and the
+/// previous state is restored afterwards. Note that this also prevents attaching
+/// debuggers as an unprivileged user.
+///
+/// Note that this setting applies to the entire process.
+#[cfg(feature = "std")]
+pub fn set_disable_core_dumps(enable: bool) {
+    secrets::set_disable_core_dumps(enable);
+}
+
+/// Information about the Raspberry Pi board.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct BoardInfo {
+    /// The serial number of the board.
+    pub serial: u64,
+    /// The revision code of the board.
+    pub revision: u32,
+}
+
+/// Obtains information about the Raspberry Pi board (only available on Linux).
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub fn board_info() -> Result<BoardInfo, io::Error> {
+    let vcio = rpi::vcio::Vcio::open()?;
+    Ok(BoardInfo {
+        serial: rpi::info::get_board_serial(&vcio)?,
+        revision: rpi::info::get_board_revision(&vcio)?,
+    })
+}
+
+/// Reads the raw values of the given customer OTP rows (relative to row 36) for
+/// debugging the OTP layout (only available on Linux).
+///
+/// Only the eight customer OTP rows can be read, the private key OTP registers are never
+/// accessible through this function. ⚠️ The customer OTP rows store the device secret
+/// when using [`SecretLocation::CustomerOtp`]. In this case, the returned values are the
+/// device secret and must never be logged or shared.
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub fn read_customer_otp_rows(rows: Range<usize>) -> Result<Vec<u32>, io::Error> {
+    let vcio = rpi::vcio::Vcio::open()?;
+    rpi::otp::read_row_range(&vcio, rows.start, rows.len())
+}
+
+/// Reads the provisioning epoch stored in the given customer OTP row and the next one
+/// (relative to row 36) for auditing (only available on Linux).
+///
+/// The epoch is not secret. Returns zero in case no epoch has been written (see
+/// [`DeriverBuilder::with_epoch_row`]).
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub fn read_epoch(row: usize) -> Result<u64, io::Error> {
+    if row >= rpi::otp::OTP_ROWS - 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Customer OTP row {} cannot store the provisioning epoch.",
+                row
+            ),
+        ));
+    }
+    let rows = read_customer_otp_rows(row..row + 2)?;
+    Ok(u64::from(rows[0]) << 32 | u64::from(rows[1]))
+}
+
+/// The state of the OTP registers storing the device secret (see [`inspect`]).
+///
+/// This never includes the device secret itself.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct OtpState {
+    /// Indicates whether the device secret has been initialized, i.e., is non-zero.
+    pub initialized: bool,
+    /// The number of non-zero OTP rows used for the device secret.
+    pub nonzero_rows: u8,
+}
+
+/// Inspects the state of the OTP registers at the given location without reading the
+/// device secret into a [`Deriver`] (only available on Linux).
+///
+/// For [`SecretLocation::Auto`], the location is detected as for
+/// [`DeriverBuilder::build`].
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub fn inspect(location: SecretLocation) -> Result<OtpState, io::Error> {
+    let vcio = rpi::vcio::Vcio::open()?;
+    let LockRetries { retries, delay } = LockRetries::default();
+    let lock = rpi::retry_lock(retries, delay, || vcio.lock_shared())?;
+    let mut store = rpi::OtpStore::new(lock, location);
+    store.resolve_location()?;
+    let secret = store.read()?;
+    Ok(OtpState {
+        initialized: rpi::is_initialized(&secret),
+        nonzero_rows: rpi::count_non_zero_rows(&secret, store.rows()) as u8,
+    })
+}
+
+/// A diagnosis of the OTP registers which can store the device secret (see
+/// [`diagnose`]).
+///
+/// This never includes any secret.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct Diagnosis {
+    /// Indicates whether the customer OTP registers have been initialized.
+    pub customer_otp_initialized: bool,
+    /// Indicates whether the private key OTP registers have been initialized.
+    pub private_key_initialized: bool,
+    /// Indicates whether both have been initialized with different secrets.
+    ///
+    /// ⚠️ In this case, the derived keys depend on the secret location.
+    pub secrets_differ: bool,
+}
+
+/// Diagnoses whether both, the private key and the customer OTP registers, have been
+/// initialized and whether they store the same secret (only available on Linux).
+///
+/// Devices with different secrets in both registers derive different keys depending on
+/// the configured [`SecretLocation`] which is most likely a misconfiguration. All eight
+/// customer OTP rows are compared in constant time. The secrets are never exposed.
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub fn diagnose() -> Result<Diagnosis, io::Error> {
+    let vcio = rpi::vcio::Vcio::open()?;
+    let LockRetries { retries, delay } = LockRetries::default();
+    let lock = rpi::retry_lock(retries, delay, || vcio.lock_shared())?;
+    let store = rpi::OtpStore::new(lock, SecretLocation::CustomerOtp);
+    let customer_otp = store.read()?;
+    let private_key = store.read_other()?.map(|(_, secret)| secret);
+    let customer_otp_initialized = rpi::is_initialized(&customer_otp);
+    let private_key_initialized =
+        matches!(&private_key, Some(secret) if rpi::is_initialized(secret));
+    let secrets_differ = match &private_key {
+        Some(private_key) if customer_otp_initialized && private_key_initialized => {
+            !bool::from(subtle::ConstantTimeEq::ct_eq(
+                customer_otp.as_slice(),
+                private_key.as_slice(),
+            ))
+        }
+        _ => false,
+    };
+    Ok(Diagnosis {
+        customer_otp_initialized,
+        private_key_initialized,
+        secrets_differ,
+    })
+}
+
+/// The minimal number of customer OTP rows used for the device secret (see
+/// [`DeriverBuilder::with_customer_otp_rows`]).
+///
+/// Four rows hold 128 random bits. With a group secret, which occupies the first four
+/// rows, four additional rows are required.
+#[cfg(feature = "std")]
+pub const MIN_CUSTOMER_OTP_ROWS: usize = 4;
+
 /// A builder for [`Deriver`].
+#[cfg(feature = "std")]
 #[derive(Debug, Clone, Default)]
 pub struct DeriverBuilder {
     /// Initialize the OTP memory.
     initialize: bool,
-    /// Use the customer-programmable OTP values instead of the OTP private key.
-    use_customer_otp: bool,
+    /// The location where the device secret is stored.
+    secret_location: SecretLocation,
     /// An optional group secret to use when initializing the device secret.
     group_secret: Option<GroupSecret>,
     /// An optional salt to use for the HKDF algorithm.
     salt: Option<Vec<u8>>,
+    /// The hash algorithm to use for the HKDF algorithm.
+    hash_algorithm: HashAlgorithm,
+    /// An optional fake device secret to use instead of the device secret.
+    fake_secret: Option<secrets::DeviceSecret>,
+    /// The customer OTP rows to use for the device secret (all rows if absent).
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    customer_otp_rows: Option<Range<usize>>,
+    /// An optional application label (or UTF-8 context) for namespacing derived keys.
+    app_label: Option<Vec<u8>>,
+    /// The retries for locking the VCIO device.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    lock_retries: LockRetries,
+    /// Wait for the exclusive lock on the VCIO device instead of retrying.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    blocking_lock: bool,
+    /// Additionally load the secret stored at the other location.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    dual_secrets: bool,
+    /// Bind all derived keys to the serial number of the board.
+    bind_to_serial: bool,
+    /// The customer OTP row storing the generation the derived keys are bound to.
+    generation_row: Option<usize>,
+    /// The first of the two customer OTP rows storing the provisioning epoch.
+    epoch_row: Option<usize>,
+    /// The provisioning epoch to write when initializing the device secret.
+    epoch: Option<u64>,
+    /// The path of the VCIO device (defaults to `/dev/vcio`).
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    vcio_path: Option<PathBuf>,
+}
+
+/// The retries for locking the VCIO device when it is locked by another process.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+struct LockRetries {
+    /// The maximal number of retries.
+    retries: u32,
+    /// The delay between retries.
+    delay: Duration,
+}
+
+#[cfg(feature = "std")]
+impl Default for LockRetries {
+    fn default() -> Self {
+        Self {
+            retries: 5,
+            delay: Duration::from_millis(100),
+        }
+    }
 }
 
+#[cfg(feature = "std")]
 impl DeriverBuilder {
     /// Creates a new [`DeriverBuilder`].
     pub fn new() -> Self {
@@ -67,12 +478,59 @@ impl DeriverBuilder {
     /// Set the optional salt to use for the HKDF algorithm.
     #[must_use]
     pub fn with_salt<S: AsRef<[u8]>>(mut self, salt: Option<S>) -> Self {
+        self.set_salt(salt);
+        self
+    }
+
+    pub fn set_salt<S: AsRef<[u8]>>(&mut self, salt: Option<S>) {
         self.salt = salt.map(|salt| salt.as_ref().into());
+    }
+
+    /// Set the salt for the HKDF algorithm from its hex encoding.
+    ///
+    /// This allows using binary salts which are not valid UTF-8. Fails with
+    /// [`BuildError::InvalidSalt`] in case the salt is not valid hex.
+    pub fn with_salt_hex(mut self, salt: &str) -> Result<Self, BuildError> {
+        self.set_salt_hex(salt)?;
+        Ok(self)
+    }
+
+    pub fn set_salt_hex(&mut self, salt: &str) -> Result<(), BuildError> {
+        self.salt = Some(decode_hex(salt).map_err(BuildError::InvalidSalt)?);
+        Ok(())
+    }
+
+    /// Set the hash algorithm to use for the HKDF algorithm.
+    ///
+    /// Note that keys derived with different hash algorithms are different.
+    #[must_use]
+    pub fn with_hash_algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.set_hash_algorithm(algorithm);
         self
     }
 
+    pub fn set_hash_algorithm(&mut self, algorithm: HashAlgorithm) {
+        self.hash_algorithm = algorithm;
+    }
+
+    /// The location where the device secret is stored.
+    pub fn secret_location(&self) -> SecretLocation {
+        self.secret_location
+    }
+
+    /// Set the location where the device secret is stored.
+    #[must_use]
+    pub fn with_secret_location(mut self, location: SecretLocation) -> Self {
+        self.set_secret_location(location);
+        self
+    }
+
+    pub fn set_secret_location(&mut self, location: SecretLocation) {
+        self.secret_location = location;
+    }
+
     pub fn use_customer_otp(&self) -> bool {
-        self.use_customer_otp
+        self.secret_location == SecretLocation::CustomerOtp
     }
 
     /// Enable the usage of the customer-programable OTP values instead of the OTP private
@@ -84,9 +542,21 @@ impl DeriverBuilder {
     }
 
     pub fn set_use_customer_otp(&mut self, enable: bool) {
-        self.use_customer_otp = enable;
+        self.set_secret_location(if enable {
+            SecretLocation::CustomerOtp
+        } else {
+            SecretLocation::PrivateKey
+        });
     }
 
+    /// Set the group secret placed in the upper 128-bits of the device secret when
+    /// initializing it.
+    ///
+    /// Devices initialized with the same group secret derive the same group keys. Note
+    /// that the group secret has no effect on an already initialized device secret.
+    /// Building fails with [`BuildError::UnusedGroupSecret`] in case a group secret is
+    /// provided without enabling [`initialize`][Self::initialize] and with
+    /// [`BuildError::InvalidGroupSecret`] in case any of its 32-bit words is zero.
     #[must_use]
     pub fn with_group_secret(mut self, secret: &[u8; 16]) -> Self {
         self.set_group_secret(secret);
@@ -97,6 +567,122 @@ impl DeriverBuilder {
         self.group_secret = Some(secret.into());
     }
 
+    /// Namespaces all keys derived with the [`Deriver`] with the provided context.
+    ///
+    /// This replaces any application label. See [`Deriver::with_context`] for details.
+    #[must_use]
+    pub fn with_context(mut self, context: Option<&str>) -> Self {
+        self.set_context(context);
+        self
+    }
+
+    pub fn set_context(&mut self, context: Option<&str>) {
+        self.app_label = context.map(|context| context.as_bytes().to_vec());
+    }
+
+    /// Namespaces all keys derived with the [`Deriver`] with the provided application
+    /// label.
+    ///
+    /// This replaces any context. See [`Deriver::with_app_label`] for details.
+    #[must_use]
+    pub fn with_app_label<L: AsRef<[u8]>>(mut self, label: Option<L>) -> Self {
+        self.set_app_label(label);
+        self
+    }
+
+    pub fn set_app_label<L: AsRef<[u8]>>(&mut self, label: Option<L>) {
+        self.app_label = label.map(|label| label.as_ref().into());
+    }
+
+    /// Restricts the customer OTP rows used for the device secret.
+    ///
+    /// The rows are relative to the first customer OTP row (row 36) and must be within
+    /// `0..8`. Only these rows are read and written such that the remaining rows can be
+    /// used for other purposes. The device secret is then formed by the words of these
+    /// rows followed by zeros. Note that using fewer rows reduces the entropy of the
+    /// device secret by 32 bits per omitted row.
+    ///
+    /// Building fails with [`BuildError::TooFewCustomerOtpRows`] in case fewer than
+    /// [`MIN_CUSTOMER_OTP_ROWS`] rows are used. With a group secret, which occupies the
+    /// first four rows, all eight rows are required as the device secret would otherwise
+    /// have less than 128 device-specific bits. Hence, with fewer rows, the device secret
+    /// has no group secret (see [`Deriver::has_group_secret`]).
+    ///
+    /// The rows only apply to [`SecretLocation::CustomerOtp`] and, in case the secret is
+    /// detected there, to [`SecretLocation::Auto`].
+    #[must_use]
+    pub fn with_customer_otp_rows(mut self, rows: Range<usize>) -> Self {
+        self.set_customer_otp_rows(rows);
+        self
+    }
+
+    pub fn set_customer_otp_rows(&mut self, rows: Range<usize>) {
+        self.customer_otp_rows = Some(rows);
+    }
+
+    /// Set the number of retries and the delay between them for locking the VCIO device
+    /// when it is locked by another process.
+    ///
+    /// By default, locking is retried 5 times with a delay of 100 milliseconds. Building
+    /// fails with [`io::ErrorKind::WouldBlock`] in case the device is still locked.
+    #[must_use]
+    pub fn with_lock_retries(mut self, retries: u32, delay: Duration) -> Self {
+        self.set_lock_retries(retries, delay);
+        self
+    }
+
+    pub fn set_lock_retries(&mut self, retries: u32, delay: Duration) {
+        self.lock_retries = LockRetries { retries, delay };
+    }
+
+    /// Wait for other processes to release their lock on the VCIO device instead of
+    /// failing after the configured [lock retries][Self::with_lock_retries].
+    ///
+    /// This applies to the exclusive lock obtained by [`build`][Self::build]. ⚠️ Note
+    /// that building then hangs indefinitely in case another process never releases its
+    /// lock, e.g., because it is stuck.
+    #[must_use]
+    pub fn with_blocking_lock(mut self, enable: bool) -> Self {
+        self.set_blocking_lock(enable);
+        self
+    }
+
+    pub fn set_blocking_lock(&mut self, enable: bool) {
+        self.blocking_lock = enable;
+    }
+
+    /// Additionally load the secret stored at the other location, if initialized.
+    ///
+    /// This supports devices storing independent secrets in the private key and the
+    /// customer OTP registers. Keys can then be derived from either secret with
+    /// [`Deriver::derive_key_from`]. Note that the secret at the other location is never
+    /// initialized by [`build`][Self::build].
+    #[must_use]
+    pub fn with_dual_secrets(mut self, enable: bool) -> Self {
+        self.set_dual_secrets(enable);
+        self
+    }
+
+    pub fn set_dual_secrets(&mut self, enable: bool) {
+        self.dual_secrets = enable;
+    }
+
+    /// Use the provided fake device secret instead of the device secret.
+    ///
+    /// The resulting [`Deriver`] behaves exactly like a [`Deriver`] for a device with
+    /// the provided device secret. No hardware is accessed.
+    ///
+    /// This is supposed to be used for testing purposes only!
+    #[must_use]
+    pub fn with_fake_secret(mut self, secret: &[u8; 32]) -> Self {
+        self.set_fake_secret(secret);
+        self
+    }
+
+    pub fn set_fake_secret(&mut self, secret: &[u8; 32]) {
+        self.fake_secret = Some(secret.into());
+    }
+
     /// Enable the automatic initialization of the OTP memory with a randomly generated
     /// secret.
     #[must_use]
@@ -109,71 +695,731 @@ impl DeriverBuilder {
         self.initialize = enable
     }
 
+    /// Bind all derived keys to the serial number of the board (disabled by default).
+    ///
+    /// The serial number is read via the VCIO property interface, also when using a fake
+    /// device secret, and mixed into the info material of every derivation (see
+    /// [`board_info`]). A copy of the device secret on a different board thus yields
+    /// different keys. ⚠️ Enabling this changes all derived keys.
+    ///
+    /// The info material is prefixed with `\x00\x00\x00\x0eserial` followed by the
+    /// serial as 64-bit big-endian integer. The binding precedes the application label or
+    /// context, if any.
+    #[must_use]
+    pub fn bind_to_serial(mut self, enable: bool) -> Self {
+        self.set_bind_to_serial(enable);
+        self
+    }
+
+    pub fn set_bind_to_serial(&mut self, enable: bool) {
+        self.bind_to_serial = enable;
+    }
+
+    /// Set the path of the VCIO device (defaults to `/dev/vcio`).
+    ///
+    /// This allows using the VCIO device at a non-standard path or a shim of it for
+    /// testing. The path is used for all accesses when building, including reading the
+    /// board serial, the generation, and the provisioning epoch.
+    #[must_use]
+    pub fn with_vcio_path(mut self, path: PathBuf) -> Self {
+        self.set_vcio_path(path);
+        self
+    }
+
+    pub fn set_vcio_path(&mut self, path: PathBuf) {
+        self.vcio_path = Some(path);
+    }
+
+    /// Opens a handle to the configured VCIO device.
+    #[cfg(target_os = "linux")]
+    fn open_vcio(&self) -> Result<rpi::vcio::Vcio, io::Error> {
+        match &self.vcio_path {
+            Some(path) => rpi::vcio::Vcio::open_path(path),
+            None => rpi::vcio::Vcio::open(),
+        }
+    }
+
+    /// Bind all derived keys to the generation stored in the given customer OTP row
+    /// (relative to row 36, disabled by default).
+    ///
+    /// The generation is a monotonic counter for rotating keys. It is encoded in unary
+    /// as the number of consecutive set bits of the row starting with the least
+    /// significant bit and advanced with [`bump_generation`][Self::bump_generation]. As
+    /// OTP bits can only be set but never be cleared, a row stores at most
+    /// [`MAX_GENERATION`] generations and a generation can never be reverted. Like the
+    /// board serial, the generation is read via the VCIO property interface, also when
+    /// using a fake device secret. The row must not be used for the device secret.
+    ///
+    /// The info material is prefixed with `\x00\x00\x00\x0egeneration` followed by the
+    /// generation as 32-bit big-endian integer. The generation follows the binding to the
+    /// board serial and precedes the application label or context, if any. ⚠️
+    /// Enabling this and bumping the generation changes all derived keys.
+    #[must_use]
+    pub fn with_generation_row(mut self, row: Option<usize>) -> Self {
+        self.set_generation_row(row);
+        self
+    }
+
+    pub fn set_generation_row(&mut self, row: Option<usize>) {
+        self.generation_row = row;
+    }
+
+    /// Irreversibly bumps the generation stored in the configured generation row and
+    /// returns the new generation.
+    ///
+    /// The next bit of the row is set under an exclusive lock (see
+    /// [`with_generation_row`][Self::with_generation_row]). Fails with
+    /// [`BuildError::NoGenerationRow`] in case no row has been configured, with
+    /// [`BuildError::InvalidGenerationRow`] in case the row is used for the device
+    /// secret, and with [`BuildError::GenerationExhausted`] in case all
+    /// [`MAX_GENERATION`] generations have been used.
+    ///
+    /// ⚠️ This operation is irreversible.
+    pub fn bump_generation(&self) -> Result<u32, BuildError> {
+        #[cfg(target_os = "linux")]
+        {
+            let vcio = self.open_vcio()?;
+            let (store, row) = self.open_generation_store(&vcio, true)?;
+            if store.read_generation(row)? >= MAX_GENERATION {
+                return Err(BuildError::GenerationExhausted);
+            }
+            Ok(store.bump_generation(row)?)
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            Err(BuildError::unsupported())
+        }
+    }
+
+    /// Opens and locks the [`rpi::OtpStore`] and checks the configured generation row.
+    #[cfg(target_os = "linux")]
+    fn open_generation_store<'vcio>(
+        &self,
+        vcio: &'vcio rpi::vcio::Vcio,
+        exclusive: bool,
+    ) -> Result<(rpi::OtpStore<'vcio>, usize), BuildError> {
+        let row = self.generation_row.ok_or(BuildError::NoGenerationRow)?;
+        let store = self.open_store(vcio, exclusive)?;
+        if row >= rpi::otp::OTP_ROWS || store.uses_customer_otp_row(row) || self.uses_epoch_row(row)
+        {
+            return Err(BuildError::InvalidGenerationRow { row });
+        }
+        Ok((store, row))
+    }
+
+    /// Bind all derived keys to the provisioning epoch stored in the given customer OTP
+    /// row and the next one (relative to row 36, disabled by default).
+    ///
+    /// The provisioning epoch is an arbitrary 64-bit value, e.g., a batch number or a
+    /// timestamp, chosen when initializing the device secret (see
+    /// [`with_epoch`][Self::with_epoch]). It is stored as two 32-bit big-endian halves,
+    /// the more significant half in the given row. As OTP bits can never be cleared, the
+    /// epoch is tamper-evident: It cannot be changed without changing all derived keys.
+    /// The epoch is not secret and can be audited with [`read_epoch`]. Like the board
+    /// serial, the epoch is read via the VCIO property interface, also when using a fake
+    /// device secret. The rows must not be used for the device secret or the generation.
+    ///
+    /// The info material is prefixed with `\x00\x00\x00\x0depoch` followed by the
+    /// epoch as 64-bit big-endian integer. The epoch follows the generation and precedes
+    /// the application label or context, if any. ⚠️ Enabling this changes all derived
+    /// keys.
+    #[must_use]
+    pub fn with_epoch_row(mut self, row: Option<usize>) -> Self {
+        self.set_epoch_row(row);
+        self
+    }
+
+    pub fn set_epoch_row(&mut self, row: Option<usize>) {
+        self.epoch_row = row;
+    }
+
+    /// Set the provisioning epoch to irreversibly write when initializing the device
+    /// secret (see [`with_epoch_row`][Self::with_epoch_row]).
+    ///
+    /// The epoch is written before the device secret and only in case the device secret
+    /// is initialized. Writing the epoch which has already been written is idempotent,
+    /// writing a different epoch fails. Building fails with [`BuildError::UnusedEpoch`]
+    /// in case an epoch is provided without enabling initialization and with
+    /// [`BuildError::NoEpochRow`] in case no epoch row has been configured.
+    ///
+    /// ⚠️ Writing the epoch is irreversible.
+    #[must_use]
+    pub fn with_epoch(mut self, epoch: Option<u64>) -> Self {
+        self.set_epoch(epoch);
+        self
+    }
+
+    pub fn set_epoch(&mut self, epoch: Option<u64>) {
+        self.epoch = epoch;
+    }
+
+    /// Checks whether the given customer OTP row is used for the provisioning epoch.
+    #[cfg(target_os = "linux")]
+    fn uses_epoch_row(&self, row: usize) -> bool {
+        self.epoch_row
+            .map_or(false, |epoch_row| epoch_row <= row && row - epoch_row < 2)
+    }
+
+    /// Checks the configured epoch row against the device secret and the generation row.
+    #[cfg(target_os = "linux")]
+    fn check_epoch_row(&self, store: &rpi::OtpStore) -> Result<usize, BuildError> {
+        let row = self.epoch_row.ok_or(BuildError::NoEpochRow)?;
+        let invalid = row >= rpi::otp::OTP_ROWS - 1
+            || store.uses_customer_otp_row(row)
+            || store.uses_customer_otp_row(row + 1)
+            || self
+                .generation_row
+                .map_or(false, |generation_row| self.uses_epoch_row(generation_row));
+        if invalid {
+            return Err(BuildError::InvalidEpochRow { row });
+        }
+        Ok(row)
+    }
+
+    /// Writes the configured provisioning epoch, if any, before initializing the device
+    /// secret.
+    #[cfg(target_os = "linux")]
+    fn write_epoch(&self, store: &rpi::OtpStore) -> Result<(), BuildError> {
+        if let Some(epoch) = self.epoch {
+            let row = self.check_epoch_row(store)?;
+            store.write_epoch(row, epoch)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the provisioning epoch stored in the configured epoch rows.
+    fn read_epoch(&self) -> Result<u64, BuildError> {
+        #[cfg(target_os = "linux")]
+        {
+            let vcio = self.open_vcio()?;
+            let store = self.open_store(&vcio, false)?;
+            let row = self.check_epoch_row(&store)?;
+            Ok(store.read_epoch(row)?)
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            Err(BuildError::unsupported())
+        }
+    }
+
+    /// Locks the VCIO device and opens the [`rpi::OtpStore`] for the configured location.
+    ///
+    /// The lock is obtained before resolving [`SecretLocation::Auto`] such that the
+    /// detection cannot race with a concurrent initialization. It is automatically
+    /// released when the store is dropped.
+    #[cfg(target_os = "linux")]
+    fn open_store<'vcio>(
+        &self,
+        vcio: &'vcio rpi::vcio::Vcio,
+        exclusive: bool,
+    ) -> Result<rpi::OtpStore<'vcio>, io::Error> {
+        let LockRetries { retries, delay } = self.lock_retries;
+        let lock = if exclusive && self.blocking_lock {
+            vcio.lock_exclusive_blocking()?
+        } else if exclusive {
+            rpi::retry_lock(retries, delay, || vcio.lock_exclusive())?
+        } else {
+            rpi::retry_lock(retries, delay, || vcio.lock_shared())?
+        };
+        let mut store = rpi::OtpStore::new(lock, self.secret_location);
+        if let Some(rows) = &self.customer_otp_rows {
+            store.set_customer_otp_rows(rows.clone());
+        }
+        store.resolve_location()?;
+        Ok(store)
+    }
+
+    /// The location reported for fake device secrets.
+    fn fake_secret_location(&self) -> SecretLocation {
+        match self.secret_location {
+            SecretLocation::Auto => SecretLocation::default(),
+            location => location,
+        }
+    }
+
+    /// The number of OTP rows a fake device secret is considered to be stored in.
+    fn fake_secret_rows(&self) -> usize {
+        match (self.fake_secret_location(), &self.customer_otp_rows) {
+            (SecretLocation::CustomerOtp, Some(rows)) => rows.len(),
+            _ => 8,
+        }
+    }
+
     /// Build a [`Deriver`].
     pub fn build(self) -> Result<Deriver, BuildError> {
+        let mut deriver = self.build_without_context()?;
+        if self.bind_to_serial {
+            deriver = deriver.bind_to_serial(self.read_board_serial()?);
+        }
+        if self.generation_row.is_some() {
+            deriver = deriver.bind_to_generation(self.read_generation()?);
+        }
+        if self.epoch_row.is_some() {
+            deriver = deriver.bind_to_epoch(self.read_epoch()?);
+        }
+        Ok(match &self.app_label {
+            Some(label) => deriver.with_app_label(label),
+            None => deriver,
+        })
+    }
+
+    /// Reads the serial number of the board for binding keys to it.
+    fn read_board_serial(&self) -> Result<u64, BuildError> {
+        #[cfg(target_os = "linux")]
+        {
+            Ok(rpi::info::get_board_serial(&self.open_vcio()?)?)
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            Err(BuildError::unsupported())
+        }
+    }
+
+    /// Reads the generation stored in the configured generation row.
+    fn read_generation(&self) -> Result<u32, BuildError> {
+        #[cfg(target_os = "linux")]
+        {
+            let vcio = self.open_vcio()?;
+            let (store, row) = self.open_generation_store(&vcio, false)?;
+            Ok(store.read_generation(row)?)
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            Err(BuildError::unsupported())
+        }
+    }
+
+    /// Build a [`Deriver`] using the existing device secret or, if it has not been
+    /// initialized, after irreversibly initializing it.
+    ///
+    /// Returns the [`Deriver`] and whether the device secret has been initialized by this
+    /// call. In contrast to [`initialize`][Self::initialize], which permits writing
+    /// whenever building, this makes the intent explicit: The device secret is read first
+    /// and only written if it is zero (see [`ensure_initialized`]). The configured group
+    /// secret, location, and customer OTP rows are used for the initialization. With a
+    /// fake device secret, nothing is ever written.
+    ///
+    /// ⚠️ Initializing the device secret is irreversible.
+    pub fn build_or_initialize(self) -> Result<(Deriver, bool), BuildError> {
+        let builder = self.initialize(true);
+        builder.validate()?;
+        let initialized = if builder.fake_secret.is_some() || fake_secret_from_env().is_some() {
+            false
+        } else {
+            builder.ensure_initialized()?
+        };
+        Ok((builder.build()?, initialized))
+    }
+
+    /// Ensures that the device secret has been initialized (see [`ensure_initialized`]).
+    fn ensure_initialized(&self) -> Result<bool, BuildError> {
+        #[cfg(target_os = "linux")]
+        {
+            let vcio = self.open_vcio()?;
+            let mut store = self.open_store(&vcio, true)?;
+            let secret = store
+                .read()
+                .map_err(|error| map_otp_error(store.location(), error))?;
+            if rpi::is_initialized(&secret) {
+                check_fully_initialized(store.rows(), &secret)?;
+                return Ok(false);
+            }
+            self.write_epoch(&store)?;
+            store
+                .initialize(self.group_secret.as_deref())
+                .map_err(|error| map_otp_error(store.location(), error))?;
+            Ok(true)
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            Err(BuildError::unsupported())
+        }
+    }
+
+    /// Validates the configuration of the builder.
+    fn validate(&self) -> Result<(), BuildError> {
+        if let Some(group_secret) = &self.group_secret {
+            if !self.initialize {
+                return Err(BuildError::UnusedGroupSecret);
+            }
+            check_group_secret(group_secret)?;
+        }
+        // The rows are unused in case the secret is stored in the private key.
+        if let (SecretLocation::CustomerOtp | SecretLocation::Auto, Some(rows)) =
+            (self.secret_location, &self.customer_otp_rows)
+        {
+            let min = if self.group_secret.is_some() {
+                4 + MIN_CUSTOMER_OTP_ROWS
+            } else {
+                MIN_CUSTOMER_OTP_ROWS
+            };
+            if rows.len() < min {
+                return Err(BuildError::TooFewCustomerOtpRows {
+                    rows: rows.len(),
+                    min,
+                });
+            }
+        }
+        if self.epoch.is_some() {
+            if !self.initialize {
+                return Err(BuildError::UnusedEpoch);
+            }
+            if self.epoch_row.is_none() {
+                return Err(BuildError::NoEpochRow);
+            }
+        }
+        Ok(())
+    }
+
+    /// Build a [`Deriver`] ignoring the context.
+    fn build_without_context(&self) -> Result<Deriver, BuildError> {
+        self.validate()?;
         let salt = self.salt.as_deref();
-        if let Ok(fake_str) = std::env::var("FAKE_RPI_DERIVE_KEY_SECRET") {
+        let hash = self.hash_algorithm;
+        if let Some(fake_secret) = &self.fake_secret {
+            let mut secret = secrets::DeviceSecret::try_new()?;
+            secret.copy_from_slice(fake_secret.as_slice());
+            return Ok(
+                Deriver::new(hash, salt, secret, self.fake_secret_location())
+                    .with_secret_rows(self.fake_secret_rows()),
+            );
+        }
+        if let Some(secret) = fake_secret_from_env() {
             // Return a `Deriver` based on the fake key.
             eprintln!("Warning! Using fake secret.");
-            let mut secret = secrets::DeviceSecret::new();
-            hex::decode_to_slice(fake_str, secret.as_mut_slice()).map_err(|err| {
-                BuildError::Other(format!(
-                    "Unable to decode `FAKE_PRI_DERIVE_KEY_SECRET`. {:?}",
-                    err
-                ))
-            })?;
-            return Ok(Deriver::new(salt, &secret));
+            let secret = secret?;
+            return Ok(
+                Deriver::new(hash, salt, secret, self.fake_secret_location())
+                    .with_secret_rows(self.fake_secret_rows()),
+            );
         }
         #[cfg(target_os = "linux")]
         {
-            let mut vcio = rpi::vcio::Vcio::open()?;
-            // Obtain an exclusive lock on the VCIO device. The lock is automatically
-            // released when `vcio` is dropped.
-            vcio.lock_exclusive()?;
-            let mut secret = if self.use_customer_otp {
-                rpi::otp::read_customer_otp(&vcio)?
-            } else {
-                rpi::otp::read_private_key(&vcio)?
-            };
-            let is_initialized = secret.as_slice() != [0; 32].as_slice();
-            if !is_initialized {
-                if self.initialize {
-                    secret = secrets::generate_device_secret();
-                    if self.use_customer_otp {
-                        rpi::otp::write_customer_otp(&vcio, &secret)?;
-                    } else {
-                        rpi::otp::write_private_key(&vcio, &secret)?;
-                    }
-                } else {
+            // Obtain an exclusive lock on the VCIO device.
+            let vcio = self.open_vcio()?;
+            let mut store = self.open_store(&vcio, true)?;
+            let mut secret = store
+                .read()
+                .map_err(|error| map_otp_error(store.location(), error))?;
+            if !rpi::is_initialized(&secret) {
+                if !self.initialize {
                     return Err(BuildError::Uninitialized);
                 }
+                // Same as `initialize` but reusing the lock obtained above.
+                self.write_epoch(&store)?;
+                secret = store
+                    .initialize(self.group_secret.as_deref())
+                    .map_err(|error| map_otp_error(store.location(), error))?;
             }
-            Ok(Deriver::new(salt, &secret))
+            check_fully_initialized(store.rows(), &secret)?;
+            let mut deriver =
+                Deriver::new(hash, salt, secret, store.location()).with_secret_rows(store.rows());
+            if self.dual_secrets {
+                let other = store
+                    .read_other()?
+                    .filter(|(_, secret)| rpi::is_initialized(secret));
+                if let Some((location, secret)) = other {
+                    check_fully_initialized(store.rows_at(location), &secret)?;
+                    deriver.set_alternate(
+                        Deriver::new(hash, salt, secret, location)
+                            .with_secret_rows(store.rows_at(location)),
+                    );
+                }
+            }
+            Ok(deriver)
         }
         #[cfg(not(target_os = "linux"))]
         {
-            Err(BuildError::Uninitialized)
+            Err(BuildError::unsupported())
+        }
+    }
+
+    /// Determines what [`build`][Self::build] would do without writing to the OTP.
+    ///
+    /// As writing the device secret is irreversible, this can be used to ask for
+    /// confirmation before initializing the device secret.
+    pub fn build_plan(&self) -> Plan {
+        if let Err(error) = self.validate() {
+            return Plan::WouldFail(error);
+        }
+        if self.fake_secret.is_some() {
+            return Plan::UseExisting;
+        }
+        if let Some(secret) = fake_secret_from_env() {
+            return match secret {
+                Ok(_) => Plan::UseExisting,
+                Err(error) => Plan::WouldFail(error),
+            };
+        }
+        #[cfg(target_os = "linux")]
+        {
+            let plan = || {
+                // A shared lock suffices as we never write to the OTP.
+                let vcio = self.open_vcio()?;
+                let store = self.open_store(&vcio, false)?;
+                let secret = store
+                    .read()
+                    .map_err(|error| map_otp_error(store.location(), error))?;
+                Ok(if rpi::is_initialized(&secret) {
+                    check_fully_initialized(store.rows(), &secret)?;
+                    Plan::UseExisting
+                } else if self.initialize {
+                    Plan::WouldInitialize(store.location())
+                } else {
+                    Plan::WouldFail(BuildError::Uninitialized)
+                })
+            };
+            plan().unwrap_or_else(Plan::WouldFail)
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            Plan::WouldFail(BuildError::unsupported())
         }
     }
+
+    /// Build a [`Deriver`] without blocking the asynchronous runtime.
+    ///
+    /// Accessing the OTP registers requires blocking I/O, hence, the [`Deriver`] is
+    /// built on Tokio's thread pool for blocking operations.
+    #[cfg(feature = "tokio")]
+    pub async fn build_async(self) -> Result<Deriver, BuildError> {
+        match tokio::task::spawn_blocking(move || self.build()).await {
+            Ok(result) => result,
+            Err(error) if error.is_panic() => std::panic::resume_unwind(error.into_panic()),
+            Err(_) => Err(BuildError::Cancelled),
+        }
+    }
+}
+
+/// The outcome of [`DeriverBuilder::build`] as determined by
+/// [`DeriverBuilder::build_plan`].
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub enum Plan {
+    /// An existing device secret would be used.
+    UseExisting,
+    /// A new device secret would be generated and irreversibly written to the OTP
+    /// registers at the given location.
+    WouldInitialize(SecretLocation),
+    /// Building would fail with the given error.
+    WouldFail(BuildError),
+}
+
+/// The environment variable for providing a fake device secret.
+#[cfg(feature = "std")]
+const FAKE_SECRET_VAR: &str = "FAKE_RPI_DERIVE_KEY_SECRET";
+
+/// Parses a fake device secret given as 64 hex characters (32 bytes).
+#[cfg(feature = "std")]
+fn parse_fake_secret(value: &str) -> Result<secrets::DeviceSecret, BuildError> {
+    check_hex_characters(value).map_err(BuildError::InvalidFakeSecret)?;
+    if value.len() != 64 {
+        return Err(BuildError::InvalidFakeSecret(HexError::InvalidLength {
+            expected: 64,
+            actual: value.len(),
+        }));
+    }
+    let mut secret = secrets::DeviceSecret::try_new()?;
+    hex::decode_to_slice(value, secret.as_mut_slice()).expect("The value has been validated.");
+    Ok(secret)
+}
+
+/// Checks that the value consists of hex characters only.
+#[cfg(feature = "std")]
+fn check_hex_characters(value: &str) -> Result<(), HexError> {
+    match value.char_indices().find(|(_, c)| !c.is_ascii_hexdigit()) {
+        Some((position, character)) => {
+            Err(HexError::InvalidCharacter {
+                character,
+                position,
+            })
+        }
+        None => Ok(()),
+    }
+}
+
+/// Decodes a hex-encoded value of arbitrary length.
+#[cfg(feature = "std")]
+fn decode_hex(value: &str) -> Result<Vec<u8>, HexError> {
+    check_hex_characters(value)?;
+    if value.len() % 2 != 0 {
+        return Err(HexError::OddLength);
+    }
+    Ok(hex::decode(value).expect("The value has been validated."))
+}
+
+/// Reads the fake device secret from the environment, if it is set.
+#[cfg(feature = "std")]
+pub(crate) fn fake_secret_from_env() -> Option<Result<secrets::DeviceSecret, BuildError>> {
+    std::env::var(FAKE_SECRET_VAR)
+        .ok()
+        .map(|value| parse_fake_secret(&value))
+}
+
+/// Maps an error of accessing the OTP registers to a [`BuildError`].
+///
+/// Firmware which does not support the private key responds with an unsuccessful
+/// status code to requests for it. Writes which cannot be completed are reported as
+/// [`BuildError::PartiallyInitialized`].
+#[cfg(all(feature = "std", target_os = "linux"))]
+fn map_otp_error(location: SecretLocation, error: io::Error) -> BuildError {
+    if let Some(partial) = rpi::as_partial_write(&error) {
+        return BuildError::PartiallyInitialized {
+            non_zero_rows: partial.non_zero_rows,
+            rows: partial.rows,
+        };
+    }
+    match location {
+        SecretLocation::PrivateKey if rpi::vcio::is_unsuccessful_response(&error) => {
+            BuildError::PrivateKeyUnsupported
+        }
+        _ => error.into(),
+    }
+}
+
+/// Checks that all device-specific rows of the device secret are non-zero.
+///
+/// A zero device-specific row indicates an interrupted or otherwise failed write. The
+/// rows which may hold a group secret are not checked as the group secret is not random.
+#[cfg(all(feature = "std", target_os = "linux"))]
+fn check_fully_initialized(rows: usize, secret: &secrets::DeviceSecret) -> Result<(), BuildError> {
+    let non_zero_rows = rpi::count_non_zero_rows(secret, rows);
+    let device_rows = rpi::device_specific_rows(rows);
+    let device_secret = &secret[4 * device_rows.start..4 * device_rows.end];
+    if device_secret.chunks(4).any(|row| row == [0; 4]) {
+        return Err(BuildError::PartiallyInitialized {
+            non_zero_rows,
+            rows,
+        });
+    }
+    Ok(())
 }
 
+/// Checks that the group secret does not contain a zero 32-bit word.
+///
+/// Each word is written to an OTP row and a zero row cannot be distinguished from a row
+/// which has not been written.
+#[cfg(feature = "std")]
+fn check_group_secret(group_secret: &[u8; 16]) -> Result<(), BuildError> {
+    if group_secret.chunks(4).any(|word| word == [0; 4]) {
+        return Err(BuildError::InvalidGroupSecret);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "std")]
 #[derive(Debug, Error)]
 pub enum BuildError {
     #[error(transparent)]
-    Io(#[from] io::Error),
+    Io(io::Error),
+    #[error(transparent)]
+    PermissionDenied(io::Error),
     #[error("Device-specific secret has not been initialized.")]
     Uninitialized,
-    #[error("{0}")]
-    Other(String),
+    #[error(
+        "Device-specific secret has only been partially initialized ({non_zero_rows} of \
+         {rows} rows are non-zero)."
+    )]
+    PartiallyInitialized { non_zero_rows: usize, rows: usize },
+    #[error("The firmware does not support storing the secret in the private key.")]
+    PrivateKeyUnsupported,
+    #[error(
+        "Using {rows} customer OTP rows for the device secret is insecure. At least {min} \
+         rows are required such that the device secret has 128 device-specific bits."
+    )]
+    TooFewCustomerOtpRows { rows: usize, min: usize },
+    #[error(
+        "A group secret has been provided without enabling initialization. The group \
+         secret only takes effect when initializing the device secret."
+    )]
+    UnusedGroupSecret,
+    #[error(
+        "The group secret contains a zero 32-bit word. Zero words cannot be distinguished \
+         from OTP rows which have not been written."
+    )]
+    InvalidGroupSecret,
+    #[error(
+        "Accessing the device secret is not supported on {platform}. Device secrets are only \
+         available on Raspberry Pi (Linux). For development, provide a fake secret instead."
+    )]
+    Unsupported { platform: &'static str },
+    #[error("No customer OTP row for storing the generation has been configured.")]
+    NoGenerationRow,
+    #[error(
+        "Customer OTP row {row} cannot store the generation. The row must exist and must not \
+         be used for the device secret."
+    )]
+    InvalidGenerationRow { row: usize },
+    #[error(
+        "All {} generations have been used. The generation cannot be bumped any further.",
+        MAX_GENERATION
+    )]
+    GenerationExhausted,
+    #[error("No customer OTP rows for storing the provisioning epoch have been configured.")]
+    NoEpochRow,
+    #[error(
+        "Customer OTP row {row} and the next row cannot store the provisioning epoch. The \
+         rows must exist and must not be used for the device secret or the generation."
+    )]
+    InvalidEpochRow { row: usize },
+    #[error(
+        "A provisioning epoch has been provided without enabling initialization. The epoch \
+         is only written when initializing the device secret."
+    )]
+    UnusedEpoch,
+    #[error("Invalid hex-encoded salt. {0}")]
+    InvalidSalt(#[source] HexError),
+    #[error("Invalid `FAKE_RPI_DERIVE_KEY_SECRET`. {0}")]
+    InvalidFakeSecret(#[source] HexError),
+    #[error("Building the deriver has been cancelled.")]
+    Cancelled,
+}
+
+/// Error decoding a hex-encoded value, e.g., a salt or a fake device secret.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[non_exhaustive]
+pub enum HexError {
+    #[error("Expected hex characters but found {character:?} at position {position}.")]
+    InvalidCharacter { character: char, position: usize },
+    #[error("Expected an even number of hex characters.")]
+    OddLength,
+    #[error("Expected {expected} hex characters ({} bytes) but got {actual}.", .expected / 2)]
+    InvalidLength { expected: usize, actual: usize },
 }
 
+#[cfg(feature = "std")]
+impl From<io::Error> for BuildError {
+    fn from(error: io::Error) -> Self {
+        #[cfg(target_os = "linux")]
+        if rpi::vcio::is_permission_denied(&error) {
+            return BuildError::PermissionDenied(error);
+        }
+        BuildError::Io(error)
+    }
+}
+
+#[cfg(feature = "std")]
+impl BuildError {
+    /// The error for accessing the device secret on the current, unsupported platform.
+    #[cfg_attr(target_os = "linux", allow(dead_code))]
+    fn unsupported() -> Self {
+        BuildError::Unsupported {
+            platform: std::env::consts::OS,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[non_exhaustive]
 pub struct Status {
     pub has_customer_otp: bool,
     pub has_private_key: bool,
 }
 
+#[cfg(feature = "std")]
 pub fn status() -> Result<Status, io::Error> {
     #[cfg(target_os = "linux")]
     {
@@ -193,67 +1439,1142 @@ pub fn status() -> Result<Status, io::Error> {
     {
         Ok(Status {
             has_customer_otp: false,
-            has_private_key: std::env::var("FAKE_RPI_DERIVE_KEY_SECRET").is_ok(),
+            has_private_key: std::env::var(FAKE_SECRET_VAR).is_ok(),
         })
     }
 }
 
-/// Error indicating that the length of the requested key is too long.
-#[derive(Error, Debug, Clone)]
-#[error("The length of the requested key is too long.")]
-pub struct InvalidLength(hkdf::InvalidLength);
+/// A record of the provisioning of a device secret with [`initialize`].
+///
+/// This never includes the device secret itself and can thus be stored in a
+/// provisioning database.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct ProvisioningReport {
+    /// The location the device secret has been written to.
+    pub location: SecretLocation,
+    /// Indicates whether a group secret has been placed in the device secret.
+    pub group_secret: bool,
+    /// The identifier of the device (see [`Deriver::device_id`]).
+    pub device_id: String,
+    /// The time of the provisioning in seconds since the UNIX epoch.
+    pub timestamp: u64,
+}
+
+/// Irreversibly initializes the device secret at the given location.
+///
+/// A new device secret is randomly generated and the optional group secret is placed in
+/// its upper 128-bits before writing it to the OTP registers under an exclusive lock. The
+/// group secret must not contain a zero 32-bit word (see
+/// [`DeriverBuilder::with_group_secret`]). In
+/// contrast to [`DeriverBuilder::build`], this function only provisions the device
+/// secret and never derives keys other than the device identifier for the returned
+/// [`ProvisioningReport`]. Fails with [`BuildError::Io`] of kind
+/// [`io::ErrorKind::AlreadyExists`] in case the device secret has already been
+/// initialized.
+///
+/// ⚠️ This operation is irreversible.
+#[cfg(feature = "std")]
+pub fn initialize(
+    location: SecretLocation,
+    group_secret: Option<&[u8; 16]>,
+) -> Result<ProvisioningReport, BuildError> {
+    initialize_with_rng_impl(location, group_secret, &mut rand::thread_rng())
+}
 
-/// A _deriver_ for deriving keys from a device secret using KHDF and SHA3-512.
-#[derive(Clone)]
-pub struct Deriver {
-    /// The HKDF structure for device-specific keys.
-    device_hkdf: hkdf::Hkdf<sha3::Sha3_512>,
-    /// The HKDF structure for group keys.
-    group_hkdf: hkdf::Hkdf<sha3::Sha3_512>,
+/// Same as [`initialize`] but generates the device secret with the provided
+/// cryptographic random number generator instead of [`rand::thread_rng`].
+///
+/// This enables using a vetted source of entropy or a seeded generator for testing.
+/// ⚠️ Never use a seeded generator for provisioning actual devices.
+#[cfg(feature = "custom-rng")]
+pub fn initialize_with_rng<R: RngCore + CryptoRng + ?Sized>(
+    location: SecretLocation,
+    group_secret: Option<&[u8; 16]>,
+    rng: &mut R,
+) -> Result<ProvisioningReport, BuildError> {
+    initialize_with_rng_impl(location, group_secret, rng)
 }
 
-impl Deriver {
-    /// Creates a new [`Deriver`] with the provided salt and secrets.
-    fn new_raw(salt: Option<&[u8]>, device_secret: &[u8], group_secret: &[u8]) -> Self {
-        Self {
-            device_hkdf: hkdf::Hkdf::new(salt, device_secret),
-            group_hkdf: hkdf::Hkdf::new(salt, group_secret),
+/// Initializes the device secret with the provided random number generator.
+#[cfg(feature = "std")]
+fn initialize_with_rng_impl<R: RngCore + CryptoRng + ?Sized>(
+    location: SecretLocation,
+    group_secret: Option<&[u8; 16]>,
+    rng: &mut R,
+) -> Result<ProvisioningReport, BuildError> {
+    if let Some(group_secret) = group_secret {
+        check_group_secret(group_secret)?;
+    }
+    #[cfg(target_os = "linux")]
+    {
+        let vcio = rpi::vcio::Vcio::open()?;
+        let LockRetries { retries, delay } = LockRetries::default();
+        let lock = rpi::retry_lock(retries, delay, || vcio.lock_exclusive())?;
+        let mut store = rpi::OtpStore::new(lock, location);
+        store.resolve_location()?;
+        let secret = store
+            .initialize_with_rng(group_secret, rng)
+            .map_err(|error| map_otp_error(store.location(), error))?;
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        let deriver = Deriver::new(HashAlgorithm::default(), None, secret, store.location());
+        Ok(ProvisioningReport {
+            location: store.location(),
+            group_secret: group_secret.is_some(),
+            device_id: deriver.device_id(),
+            timestamp,
+        })
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (location, group_secret, rng);
+        Err(BuildError::unsupported())
+    }
+}
+
+/// Ensures that the device secret at the given location has been initialized.
+///
+/// Returns `true` in case the device secret has been initialized by this call and
+/// `false` in case it has already been initialized. In contrast to [`initialize`], this
+/// is idempotent and can thus be called on every boot. The device secret is read and,
+/// if it is zero, written under an exclusive lock such that concurrent calls never
+/// write it twice. Fails with [`BuildError::PartiallyInitialized`] in case the device
+/// secret has only been partially initialized.
+///
+/// ⚠️ Initializing the device secret is irreversible.
+#[cfg(feature = "std")]
+pub fn ensure_initialized(location: SecretLocation) -> Result<bool, BuildError> {
+    DeriverBuilder::new()
+        .with_secret_location(location)
+        .ensure_initialized()
+}
+
+/// The info used for deriving the device identifier.
+pub(crate) const DEVICE_ID_INFO: &str = "rpi-derive-key/device-id";
+
+/// Obtains a stable opaque identifier of the device, analogous to `/etc/machine-id`.
+///
+/// See [`Deriver::device_id`] for details.
+#[cfg(feature = "std")]
+pub fn device_id() -> Result<String, BuildError> {
+    Ok(DeriverBuilder::new().build()?.device_id())
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    /// The fake device secret used for testing.
+    pub(crate) const FAKE_SECRET: [u8; 32] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d,
+        0x1e, 0x1f,
+    ];
+
+    /// Tests that [`DeriverBuilder::with_fake_secret`] produces the same keys as the
+    /// real code path.
+    #[test]
+    fn test_fake_secret() {
+        let deriver = DeriverBuilder::new()
+            .with_salt(Some("salt"))
+            .with_fake_secret(&FAKE_SECRET)
+            .build()
+            .unwrap();
+        let expected = Deriver::new(
+            HashAlgorithm::default(),
+            Some(b"salt"),
+            secrets::DeviceSecret::from(&FAKE_SECRET),
+            SecretLocation::PrivateKey,
+        );
+        assert_eq!(
+            deriver.derive_key_vec("info", 32).unwrap(),
+            expected.derive_key_vec("info", 32).unwrap()
+        );
+        assert_eq!(
+            deriver.derive_group_key_vec("info", 32).unwrap(),
+            expected.derive_group_key_vec("info", 32).unwrap()
+        );
+    }
+
+    /// Tests building a [`Deriver`] asynchronously.
+    #[cfg(feature = "tokio")]
+    #[test]
+    pub fn test_build_async() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let deriver = runtime
+            .block_on(
+                DeriverBuilder::new()
+                    .with_fake_secret(&FAKE_SECRET)
+                    .build_async(),
+            )
+            .unwrap();
+        let expected = DeriverBuilder::new()
+            .with_fake_secret(&FAKE_SECRET)
+            .build()
+            .unwrap();
+        assert_eq!(
+            deriver.derive_key_vec(b"info", 32).unwrap(),
+            expected.derive_key_vec(b"info", 32).unwrap()
+        );
+    }
+
+    /// Tests the mapping of unsupported private key errors.
+    #[cfg(target_os = "linux")]
+    #[test]
+    pub fn test_private_key_unsupported() {
+        let unsupported = || rpi::vcio::check_response(&[8, 0x80000001]).unwrap_err();
+        assert!(matches!(
+            map_otp_error(SecretLocation::PrivateKey, unsupported()),
+            BuildError::PrivateKeyUnsupported
+        ));
+        assert!(matches!(
+            map_otp_error(SecretLocation::CustomerOtp, unsupported()),
+            BuildError::Io(_)
+        ));
+        assert!(matches!(
+            map_otp_error(
+                SecretLocation::PrivateKey,
+                io::Error::from(io::ErrorKind::WouldBlock)
+            ),
+            BuildError::Io(_)
+        ));
+    }
+
+    /// Tests the build plan for fake secrets.
+    #[test]
+    pub fn test_build_plan() {
+        let builder = DeriverBuilder::new()
+            .with_fake_secret(&FAKE_SECRET)
+            .initialize(true);
+        assert!(matches!(builder.build_plan(), Plan::UseExisting));
+    }
+
+    /// Tests that too few customer OTP rows for the device secret are refused.
+    #[test]
+    fn test_too_few_customer_otp_rows() {
+        let builder = DeriverBuilder::new()
+            .with_fake_secret(&FAKE_SECRET)
+            .with_secret_location(SecretLocation::CustomerOtp);
+        assert!(matches!(
+            builder.clone().with_customer_otp_rows(0..3).build(),
+            Err(BuildError::TooFewCustomerOtpRows { rows: 3, min: 4 })
+        ));
+        assert!(matches!(
+            builder.clone().with_customer_otp_rows(0..3).build_plan(),
+            Plan::WouldFail(BuildError::TooFewCustomerOtpRows { rows: 3, min: 4 })
+        ));
+        assert!(builder.clone().with_customer_otp_rows(2..6).build().is_ok());
+        // The rows are unused for the private key.
+        assert!(builder
+            .clone()
+            .with_secret_location(SecretLocation::PrivateKey)
+            .with_customer_otp_rows(0..3)
+            .build()
+            .is_ok());
+        // With a group secret, all rows are required.
+        let builder = builder.initialize(true).with_group_secret(&[0xab; 16]);
+        assert!(matches!(
+            builder.clone().with_customer_otp_rows(0..4).build(),
+            Err(BuildError::TooFewCustomerOtpRows { rows: 4, min: 8 })
+        ));
+        assert!(matches!(
+            builder.clone().with_customer_otp_rows(0..7).build_plan(),
+            Plan::WouldFail(BuildError::TooFewCustomerOtpRows { rows: 7, min: 8 })
+        ));
+        assert!(builder.with_customer_otp_rows(0..8).build().is_ok());
+    }
+
+    /// Tests the serialization of [`Status`] and [`SecretLocation`].
+    #[cfg(feature = "serde")]
+    #[test]
+    pub fn test_serde() {
+        let status = Status {
+            has_customer_otp: false,
+            has_private_key: true,
+        };
+        assert_eq!(
+            serde_json::to_string(&status).unwrap(),
+            r#"{"has_customer_otp":false,"has_private_key":true}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&SecretLocation::CustomerOtp).unwrap(),
+            r#""customer_otp""#
+        );
+        assert_eq!(
+            serde_json::from_str::<SecretLocation>(r#""private_key""#).unwrap(),
+            SecretLocation::PrivateKey
+        );
+    }
+
+    /// Tests that the resolved secret location is recorded on the [`Deriver`].
+    #[test]
+    fn test_secret_location() {
+        for (location, expected) in [
+            (SecretLocation::PrivateKey, SecretLocation::PrivateKey),
+            (SecretLocation::CustomerOtp, SecretLocation::CustomerOtp),
+            (SecretLocation::Auto, SecretLocation::PrivateKey),
+        ] {
+            let deriver = DeriverBuilder::new()
+                .with_fake_secret(&FAKE_SECRET)
+                .with_secret_location(location)
+                .build()
+                .unwrap();
+            assert_eq!(deriver.secret_location(), expected);
         }
     }
 
-    /// Creates a new [`Deriver`] with the provided salt and device secret.
-    fn new(salt: Option<&[u8]>, secret: &secrets::DeviceSecret) -> Self {
-        Self::new_raw(salt, secret.as_slice(), secrets::get_group_secret(secret))
+    /// Tests the validation of fake device secrets provided via the environment.
+    #[test]
+    fn test_parse_fake_secret() {
+        let valid = hex::encode(FAKE_SECRET);
+        assert_eq!(
+            parse_fake_secret(&valid).unwrap().as_slice(),
+            FAKE_SECRET.as_slice()
+        );
+        assert_eq!(
+            parse_fake_secret(&valid.to_uppercase()).unwrap().as_slice(),
+            FAKE_SECRET.as_slice()
+        );
+        let error = parse_fake_secret(&valid[..62]).unwrap_err().to_string();
+        assert!(error.contains("`FAKE_RPI_DERIVE_KEY_SECRET`"));
+        assert!(error.contains("Expected 64 hex characters (32 bytes) but got 62."));
+        let error = parse_fake_secret(&format!("{}00", valid))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("but got 66."));
+        let error = parse_fake_secret("debug").unwrap_err();
+        assert!(error.to_string().contains("found 'u' at position 3."));
+        assert!(matches!(
+            std::error::Error::source(&error).and_then(|source| source.downcast_ref::<HexError>()),
+            Some(HexError::InvalidCharacter {
+                character: 'u',
+                position: 3
+            })
+        ));
+        assert!(parse_fake_secret("").is_err());
+        assert!(parse_fake_secret(&format!("{}é", &valid[..63])).is_err());
     }
 
-    /// Crates a new fake [`Deriver`] with the provided salt and device secret.
-    ///
-    /// This is supposed to be used for testing purposes only!
-    pub fn new_fake(salt: Option<&[u8]>, secret: &[u8; 32]) -> Self {
-        Self::new_raw(salt, secret.as_slice(), &secret[..16])
+    /// Tests that providing a group secret without initialization is rejected.
+    #[test]
+    fn test_unused_group_secret() {
+        let builder = DeriverBuilder::new()
+            .with_fake_secret(&FAKE_SECRET)
+            .with_group_secret(&[0xAB; 16]);
+        assert!(matches!(
+            builder.clone().build(),
+            Err(BuildError::UnusedGroupSecret)
+        ));
+        assert!(matches!(
+            builder.build_plan(),
+            Plan::WouldFail(BuildError::UnusedGroupSecret)
+        ));
+        assert!(builder.initialize(true).build().is_ok());
     }
 
-    /// Derive a device-specific key.
-    pub fn derive_key<I: AsRef<[u8]>>(&self, info: I, key: &mut [u8]) -> Result<(), InvalidLength> {
-        self.device_hkdf
-            .expand(info.as_ref(), key)
-            .map_err(InvalidLength)
+    /// Tests that group secrets with a zero 32-bit word are rejected.
+    #[test]
+    fn test_invalid_group_secret() {
+        let mut group_secret = [0xAB; 16];
+        group_secret[4..8].copy_from_slice(&[0; 4]);
+        let builder = DeriverBuilder::new()
+            .with_fake_secret(&FAKE_SECRET)
+            .initialize(true)
+            .with_group_secret(&group_secret);
+        assert!(matches!(
+            builder.clone().build(),
+            Err(BuildError::InvalidGroupSecret)
+        ));
+        assert!(matches!(
+            builder.build_plan(),
+            Plan::WouldFail(BuildError::InvalidGroupSecret)
+        ));
+        assert!(matches!(
+            initialize(SecretLocation::PrivateKey, Some(&group_secret)),
+            Err(BuildError::InvalidGroupSecret)
+        ));
     }
 
-    /// Derive a group key (using the upper 128-bits of the device secret).
-    pub fn derive_group_key<I: AsRef<[u8]>>(
-        &self,
-        info: I,
-        key: &mut [u8],
-    ) -> Result<(), InvalidLength> {
-        self.group_hkdf
-            .expand(info.as_ref(), key)
-            .map_err(InvalidLength)
+    /// Tests that only the device-specific rows must be non-zero using the mocked VCIO
+    /// device.
+    #[cfg(all(feature = "mock-vcio", target_os = "linux"))]
+    #[test]
+    fn test_mock_zero_group_secret_row() {
+        let _guard = rpi::mock::exclusive();
+        rpi::mock::reset(true);
+        // Devices may have been initialized with a group secret with a zero word.
+        rpi::mock::state().private_key = [1, 0, 1, 1, 1, 1, 1, 1];
+        assert!(DeriverBuilder::new().build().unwrap().has_group_secret());
+        assert!(matches!(
+            DeriverBuilder::new().build_plan(),
+            Plan::UseExisting
+        ));
+        rpi::mock::reset(true);
+        rpi::mock::state().private_key = [1, 1, 1, 1, 1, 0, 1, 1];
+        assert!(matches!(
+            DeriverBuilder::new().build(),
+            Err(BuildError::PartiallyInitialized {
+                non_zero_rows: 7,
+                rows: 8
+            })
+        ));
+        rpi::mock::reset(true);
+        let mut group_secret = [0xAB; 16];
+        group_secret[12..].copy_from_slice(&[0; 4]);
+        assert!(matches!(
+            DeriverBuilder::new()
+                .initialize(true)
+                .with_group_secret(&group_secret)
+                .build(),
+            Err(BuildError::InvalidGroupSecret)
+        ));
+        assert_eq!(rpi::mock::state().private_key, [0; 8]);
+    }
+
+    /// Tests building a [`Deriver`] end-to-end using the mocked VCIO device.
+    #[cfg(all(feature = "mock-vcio", target_os = "linux"))]
+    #[test]
+    fn test_mock_build() {
+        let _guard = rpi::mock::exclusive();
+        rpi::mock::reset(true);
+        assert!(matches!(
+            DeriverBuilder::new().build(),
+            Err(BuildError::Uninitialized)
+        ));
+        assert!(matches!(
+            DeriverBuilder::new().initialize(true).build_plan(),
+            Plan::WouldInitialize(SecretLocation::PrivateKey)
+        ));
+        let initialized = DeriverBuilder::new().initialize(true).build().unwrap();
+        let existing = DeriverBuilder::new().build().unwrap();
+        assert_eq!(
+            initialized.derive_key_vec("info", 32).unwrap(),
+            existing.derive_key_vec("info", 32).unwrap()
+        );
+        assert!(matches!(
+            DeriverBuilder::new().build_plan(),
+            Plan::UseExisting
+        ));
+        let state = inspect(SecretLocation::PrivateKey).unwrap();
+        assert!(state.initialized);
+        assert_eq!(state.nonzero_rows, 8);
+
+        rpi::mock::reset(false);
+        assert!(matches!(
+            DeriverBuilder::new().initialize(true).build(),
+            Err(BuildError::PrivateKeyUnsupported)
+        ));
+        rpi::mock::state().customer_otp[0] = 1;
+        assert!(matches!(
+            DeriverBuilder::new().with_use_customer_otp(true).build(),
+            Err(BuildError::PartiallyInitialized {
+                non_zero_rows: 1,
+                rows: 8
+            })
+        ));
+        let info = board_info().unwrap();
+        assert_eq!(info.serial, 0x1234abcd);
+        assert_eq!(info.revision, 0x00c03111);
+    }
+
+    /// Tests loading both secrets using the mocked VCIO device.
+    #[cfg(all(feature = "mock-vcio", target_os = "linux"))]
+    #[test]
+    fn test_mock_dual_secrets() {
+        let _guard = rpi::mock::exclusive();
+        rpi::mock::reset(true);
+        let private_key = DeriverBuilder::new().initialize(true).build().unwrap();
+        let customer_otp = DeriverBuilder::new()
+            .with_use_customer_otp(true)
+            .initialize(true)
+            .build()
+            .unwrap();
+        let deriver = DeriverBuilder::new()
+            .with_dual_secrets(true)
+            .with_context(Some("context"))
+            .build()
+            .unwrap();
+        for (location, expected) in [
+            (SecretLocation::PrivateKey, &private_key),
+            (SecretLocation::CustomerOtp, &customer_otp),
+        ] {
+            let mut key = [0; 32];
+            deriver.derive_key_from(location, "info", &mut key).unwrap();
+            let expected = expected.clone().with_context("context");
+            assert_eq!(key.as_slice(), expected.derive_key_vec("info", 32).unwrap());
+        }
+    }
+
+    /// Tests that the error for unsupported platforms names the platform.
+    #[test]
+    fn test_unsupported() {
+        let error = BuildError::unsupported();
+        assert!(error.to_string().contains(std::env::consts::OS));
+        #[cfg(not(target_os = "linux"))]
+        assert!(matches!(
+            DeriverBuilder::new().build(),
+            Err(BuildError::Unsupported { .. })
+        ));
+    }
+
+    /// Tests the report of provisioning a device secret using the mocked VCIO device.
+    #[cfg(all(feature = "mock-vcio", target_os = "linux"))]
+    #[test]
+    fn test_mock_provisioning_report() {
+        let _guard = rpi::mock::exclusive();
+        rpi::mock::reset(false);
+        let report = initialize(SecretLocation::Auto, Some(&[0xab; 16])).unwrap();
+        assert_eq!(report.location, SecretLocation::CustomerOtp);
+        assert!(report.group_secret);
+        assert!(report.timestamp > 0);
+        let deriver = DeriverBuilder::new()
+            .with_secret_location(SecretLocation::Auto)
+            .build()
+            .unwrap();
+        assert_eq!(report.device_id, deriver.device_id());
+        assert!(matches!(
+            initialize(SecretLocation::CustomerOtp, None),
+            Err(BuildError::Io(error)) if error.kind() == io::ErrorKind::AlreadyExists
+        ));
+    }
+
+    /// Tests checking the firmware revision using the mocked VCIO device.
+    #[cfg(all(feature = "mock-vcio", target_os = "linux"))]
+    #[test]
+    fn test_mock_firmware_check() {
+        let _guard = rpi::mock::exclusive();
+        for supported in [true, false] {
+            rpi::mock::reset(supported);
+            let check = verify_firmware_min_version().unwrap();
+            assert_eq!(check.revision, firmware_revision().unwrap());
+            assert_eq!(check.min_revision, MIN_PRIVATE_KEY_FIRMWARE_REVISION);
+            assert_eq!(check.supports_private_key, supported);
+            assert_eq!(supports_private_key(), supported);
+        }
+    }
+
+    /// Tests setting the salt from its hex encoding.
+    #[test]
+    fn test_salt_hex() {
+        let derive = |builder: DeriverBuilder| {
+            builder
+                .with_fake_secret(&FAKE_SECRET)
+                .build()
+                .unwrap()
+                .derive_key_vec("info", 32)
+                .unwrap()
+        };
+        assert_eq!(
+            derive(DeriverBuilder::new().with_salt_hex("73616c74").unwrap()),
+            derive(DeriverBuilder::new().with_salt(Some("salt")))
+        );
+        assert_eq!(
+            derive(DeriverBuilder::new().with_salt_hex("FF00").unwrap()),
+            Deriver::new_fake(Some(&[0xff, 0x00]), &FAKE_SECRET)
+                .derive_key_vec("info", 32)
+                .unwrap()
+        );
+        for (invalid, expected) in [
+            ("abc", HexError::OddLength),
+            (
+                "xy",
+                HexError::InvalidCharacter {
+                    character: 'x',
+                    position: 0,
+                },
+            ),
+            (
+                "00 11",
+                HexError::InvalidCharacter {
+                    character: ' ',
+                    position: 2,
+                },
+            ),
+        ] {
+            let error = DeriverBuilder::new().with_salt_hex(invalid).unwrap_err();
+            assert!(error.to_string().contains("Invalid hex-encoded salt."));
+            assert!(matches!(error, BuildError::InvalidSalt(error) if error == expected));
+        }
+    }
+
+    /// Tests idempotent initialization using the mocked VCIO device.
+    #[cfg(all(feature = "mock-vcio", target_os = "linux"))]
+    #[test]
+    fn test_mock_ensure_initialized() {
+        let _guard = rpi::mock::exclusive();
+        rpi::mock::reset(true);
+        assert!(ensure_initialized(SecretLocation::Auto).unwrap());
+        let secret = rpi::mock::state().private_key;
+        assert!(!ensure_initialized(SecretLocation::Auto).unwrap());
+        assert!(!ensure_initialized(SecretLocation::PrivateKey).unwrap());
+        assert_eq!(rpi::mock::state().private_key, secret);
+        rpi::mock::state().customer_otp[0] = 1;
+        assert!(matches!(
+            ensure_initialized(SecretLocation::CustomerOtp),
+            Err(BuildError::PartiallyInitialized { .. })
+        ));
+        rpi::mock::reset(false);
+        assert!(matches!(
+            ensure_initialized(SecretLocation::PrivateKey),
+            Err(BuildError::PrivateKeyUnsupported)
+        ));
+        assert!(ensure_initialized(SecretLocation::Auto).unwrap());
+    }
+
+    /// Tests that device secrets stored in fewer than eight customer OTP rows have no
+    /// group secret.
+    #[test]
+    fn test_customer_otp_rows_group_secret() {
+        let builder = DeriverBuilder::new()
+            .with_fake_secret(&FAKE_SECRET)
+            .with_secret_location(SecretLocation::CustomerOtp);
+        assert!(builder
+            .clone()
+            .with_customer_otp_rows(0..8)
+            .build()
+            .unwrap()
+            .has_group_secret());
+        let mut zero_group = FAKE_SECRET;
+        zero_group[..16].fill(0);
+        let expected = Deriver::new_fake(None, &zero_group)
+            .derive_group_key_vec("info", 32)
+            .unwrap();
+        for rows in [0..4, 2..6, 1..8] {
+            let deriver = builder
+                .clone()
+                .with_customer_otp_rows(rows)
+                .build()
+                .unwrap();
+            assert!(!deriver.has_group_secret());
+            assert!(!deriver.clone_with_salt(Some(b"salt")).has_group_secret());
+            assert_eq!(deriver.derive_group_key_vec("info", 32).unwrap(), expected);
+        }
+        // The rows are unused for the private key.
+        assert!(builder
+            .with_secret_location(SecretLocation::PrivateKey)
+            .with_customer_otp_rows(0..4)
+            .build()
+            .unwrap()
+            .has_group_secret());
+    }
+
+    /// Tests that denied access to the VCIO device is reported as dedicated error.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_permission_denied() {
+        let error = io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            rpi::vcio::PermissionDenied {
+                path: "/dev/vcio".into(),
+            },
+        );
+        let error = BuildError::from(error);
+        assert!(matches!(error, BuildError::PermissionDenied(_)));
+        assert!(error.to_string().contains("sufficient privileges"));
+        assert!(error.to_string().contains("`/dev/vcio`"));
+        assert!(matches!(
+            BuildError::from(io::Error::from(io::ErrorKind::PermissionDenied)),
+            BuildError::Io(_)
+        ));
+    }
+
+    /// Tests binding keys to the board serial using the mocked VCIO device.
+    #[cfg(all(feature = "mock-vcio", target_os = "linux"))]
+    #[test]
+    fn test_mock_bind_to_serial() {
+        let _guard = rpi::mock::exclusive();
+        rpi::mock::reset(true);
+        let build = |bind: bool| {
+            DeriverBuilder::new()
+                .with_fake_secret(&FAKE_SECRET)
+                .with_context(Some("context"))
+                .bind_to_serial(bind)
+                .build()
+                .unwrap()
+        };
+        let unbound = build(false);
+        let bound = build(true);
+        assert_ne!(
+            bound.derive_key_vec("info", 32).unwrap(),
+            unbound.derive_key_vec("info", 32).unwrap()
+        );
+        // The framing is documented such that it can be reproduced.
+        let mut info = b"\x00\x00\x00\x0eserial".to_vec();
+        info.extend_from_slice(&0x1234abcdu64.to_be_bytes());
+        info.extend_from_slice(b"\x00\x00\x00\x07contextinfo");
+        let plain = Deriver::new_fake(None, &FAKE_SECRET);
+        assert_eq!(
+            bound.derive_key_vec("info", 32).unwrap(),
+            plain.derive_key_vec(&info, 32).unwrap()
+        );
+        assert_eq!(
+            bound.derive_group_key_vec("info", 32).unwrap(),
+            plain.derive_group_key_vec(&info, 32).unwrap()
+        );
+        // The binding never collides with an application label.
+        let builder = DeriverBuilder::new().with_fake_secret(&FAKE_SECRET);
+        let labeled = builder
+            .clone()
+            .with_app_label(Some(0x1234abcdu64.to_be_bytes()))
+            .build()
+            .unwrap();
+        assert_ne!(
+            builder
+                .bind_to_serial(true)
+                .build()
+                .unwrap()
+                .derive_key_vec("info", 32)
+                .unwrap(),
+            labeled.derive_key_vec("info", 32).unwrap()
+        );
+        // A different board yields different keys.
+        rpi::mock::state().board_serial = 0x5678;
+        assert_ne!(
+            build(true).derive_key_vec("info", 32).unwrap(),
+            bound.derive_key_vec("info", 32).unwrap()
+        );
+    }
+
+    /// Tests initializing the device secret with a seeded random number generator using
+    /// the mocked VCIO device.
+    #[cfg(all(feature = "mock-vcio", feature = "custom-rng", target_os = "linux"))]
+    #[test]
+    fn test_mock_initialize_with_rng() {
+        use rand::SeedableRng;
+
+        let _guard = rpi::mock::exclusive();
+        rpi::mock::reset(true);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let report = initialize_with_rng(SecretLocation::PrivateKey, None, &mut rng).unwrap();
+        let mut expected = [0; 32];
+        rand::rngs::StdRng::seed_from_u64(42).fill_bytes(&mut expected);
+        let deriver = DeriverBuilder::new().build().unwrap();
+        assert_eq!(
+            deriver.derive_key_vec("info", 32).unwrap(),
+            Deriver::new_fake(None, &expected)
+                .derive_key_vec("info", 32)
+                .unwrap()
+        );
+        assert_eq!(
+            report.device_id,
+            Deriver::new_fake(None, &expected).device_id()
+        );
+    }
+
+    /// Tests binding keys to the generation and bumping it using the mocked VCIO device.
+    #[cfg(all(feature = "mock-vcio", target_os = "linux"))]
+    #[test]
+    fn test_mock_generation() {
+        let _guard = rpi::mock::exclusive();
+        rpi::mock::reset(true);
+        let builder = DeriverBuilder::new()
+            .with_fake_secret(&FAKE_SECRET)
+            .with_generation_row(Some(7));
+        let initial = builder.clone().build().unwrap();
+        assert_eq!(initial.generation(), Some(0));
+        assert_eq!(builder.bump_generation().unwrap(), 1);
+        assert_eq!(rpi::mock::state().customer_otp[7], 0b1);
+        let bumped = builder.clone().build().unwrap();
+        assert_eq!(bumped.generation(), Some(1));
+        assert_ne!(
+            bumped.derive_key_vec("info", 32).unwrap(),
+            initial.derive_key_vec("info", 32).unwrap()
+        );
+        // The framing is documented such that it can be reproduced.
+        let plain = Deriver::new_fake(None, &FAKE_SECRET);
+        assert_eq!(
+            bumped.derive_key_vec("info", 32).unwrap(),
+            plain
+                .derive_key_vec(b"\x00\x00\x00\x0egeneration\x00\x00\x00\x01info", 32)
+                .unwrap()
+        );
+        // The generation never collides with an application label.
+        assert_ne!(
+            initial.derive_key_vec("info", 32).unwrap(),
+            DeriverBuilder::new()
+                .with_fake_secret(&FAKE_SECRET)
+                .with_app_label(Some([0; 4]))
+                .build()
+                .unwrap()
+                .derive_key_vec("info", 32)
+                .unwrap()
+        );
+        assert_eq!(builder.bump_generation().unwrap(), 2);
+        assert_eq!(rpi::mock::state().customer_otp[7], 0b11);
+        // Without a generation row, keys are not bound to any generation.
+        assert_eq!(
+            DeriverBuilder::new()
+                .with_fake_secret(&FAKE_SECRET)
+                .build()
+                .unwrap()
+                .generation(),
+            None
+        );
+        // All generations have been used.
+        rpi::mock::state().customer_otp[7] = u32::MAX;
+        assert_eq!(
+            builder.clone().build().unwrap().generation(),
+            Some(MAX_GENERATION)
+        );
+        assert!(matches!(
+            builder.bump_generation(),
+            Err(BuildError::GenerationExhausted)
+        ));
+        // Rows used for the device secret or not existing are refused.
+        assert!(matches!(
+            DeriverBuilder::new().bump_generation(),
+            Err(BuildError::NoGenerationRow)
+        ));
+        assert!(matches!(
+            builder
+                .clone()
+                .with_secret_location(SecretLocation::CustomerOtp)
+                .with_generation_row(Some(3))
+                .bump_generation(),
+            Err(BuildError::InvalidGenerationRow { row: 3 })
+        ));
+        assert!(matches!(
+            builder.clone().with_generation_row(Some(8)).build(),
+            Err(BuildError::InvalidGenerationRow { row: 8 })
+        ));
+        // Rows not used for the device secret can store the generation.
+        assert_eq!(
+            builder
+                .clone()
+                .with_secret_location(SecretLocation::CustomerOtp)
+                .with_customer_otp_rows(0..4)
+                .with_generation_row(Some(4))
+                .bump_generation()
+                .unwrap(),
+            1
+        );
+    }
+
+    /// Tests diagnosing differing secrets using the mocked VCIO device.
+    #[cfg(all(feature = "mock-vcio", target_os = "linux"))]
+    #[test]
+    fn test_mock_diagnose() {
+        let _guard = rpi::mock::exclusive();
+        rpi::mock::reset(true);
+        let diagnosis = diagnose().unwrap();
+        assert!(!diagnosis.customer_otp_initialized);
+        assert!(!diagnosis.private_key_initialized);
+        assert!(!diagnosis.secrets_differ);
+        rpi::mock::state().customer_otp = [1, 2, 3, 4, 5, 6, 7, 8];
+        let diagnosis = diagnose().unwrap();
+        assert!(diagnosis.customer_otp_initialized);
+        assert!(!diagnosis.private_key_initialized);
+        assert!(!diagnosis.secrets_differ);
+        rpi::mock::state().private_key = [1, 2, 3, 4, 5, 6, 7, 8];
+        let diagnosis = diagnose().unwrap();
+        assert!(diagnosis.private_key_initialized);
+        assert!(!diagnosis.secrets_differ);
+        rpi::mock::state().private_key[7] = 9;
+        assert!(diagnose().unwrap().secrets_differ);
+        // Firmware without support for the private key.
+        rpi::mock::reset(false);
+        rpi::mock::state().customer_otp = [1, 2, 3, 4, 5, 6, 7, 8];
+        let diagnosis = diagnose().unwrap();
+        assert!(diagnosis.customer_otp_initialized);
+        assert!(!diagnosis.private_key_initialized);
+        assert!(!diagnosis.secrets_differ);
+    }
+
+    /// Tests reading raw customer OTP rows using the mocked VCIO device.
+    #[cfg(all(feature = "mock-vcio", target_os = "linux"))]
+    #[test]
+    fn test_mock_read_customer_otp_rows() {
+        let _guard = rpi::mock::exclusive();
+        rpi::mock::reset(true);
+        rpi::mock::state().customer_otp = [1, 2, 3, 4, 5, 6, 7, 8];
+        rpi::mock::state().private_key = [9; 8];
+        assert_eq!(
+            read_customer_otp_rows(0..8).unwrap(),
+            [1, 2, 3, 4, 5, 6, 7, 8]
+        );
+        assert_eq!(read_customer_otp_rows(2..5).unwrap(), [3, 4, 5]);
+        assert!(read_customer_otp_rows(4..4).is_err());
+        assert!(read_customer_otp_rows(6..9).is_err());
+    }
+
+    /// Tests that a subset of the customer OTP rows leaves no group secret using the
+    /// mocked VCIO device.
+    #[cfg(all(feature = "mock-vcio", target_os = "linux"))]
+    #[test]
+    fn test_mock_customer_otp_rows_group_secret() {
+        let _guard = rpi::mock::exclusive();
+        rpi::mock::reset(true);
+        rpi::mock::state().customer_otp = [1, 2, 3, 4, 5, 6, 7, 8];
+        let builder = DeriverBuilder::new().with_secret_location(SecretLocation::CustomerOtp);
+        assert!(builder.clone().build().unwrap().has_group_secret());
+        let deriver = builder.with_customer_otp_rows(2..8).build().unwrap();
+        assert!(!deriver.has_group_secret());
+    }
+
+    /// Tests building with initialization on demand using the mocked VCIO device.
+    #[cfg(all(feature = "mock-vcio", target_os = "linux"))]
+    #[test]
+    fn test_mock_build_or_initialize() {
+        let _guard = rpi::mock::exclusive();
+        rpi::mock::reset(true);
+        let group_secret = [0xab; 16];
+        let builder = DeriverBuilder::new().with_group_secret(&group_secret);
+        let (deriver, initialized) = builder.clone().build_or_initialize().unwrap();
+        assert!(initialized);
+        assert!(deriver.has_group_secret());
+        let (again, initialized) = builder.build_or_initialize().unwrap();
+        assert!(!initialized);
+        assert_eq!(
+            again.derive_key_vec("info", 32).unwrap(),
+            deriver.derive_key_vec("info", 32).unwrap()
+        );
+        assert_eq!(
+            again.derive_group_key_vec("info", 32).unwrap(),
+            Deriver::new_fake(None, &[[0xab; 16], [0; 16]].concat().try_into().unwrap())
+                .derive_group_key_vec("info", 32)
+                .unwrap()
+        );
+        // Fake device secrets are never written.
+        rpi::mock::reset(true);
+        let (_, initialized) = DeriverBuilder::new()
+            .with_fake_secret(&FAKE_SECRET)
+            .build_or_initialize()
+            .unwrap();
+        assert!(!initialized);
+        assert_eq!(rpi::mock::state().private_key, [0; 8]);
+    }
+
+    /// Tests writing and binding keys to the provisioning epoch using the mocked VCIO
+    /// device.
+    #[cfg(all(feature = "mock-vcio", target_os = "linux"))]
+    #[test]
+    fn test_mock_epoch() {
+        let _guard = rpi::mock::exclusive();
+        rpi::mock::reset(true);
+        let epoch = 0x0123_4567_89ab_cdef;
+        let builder = DeriverBuilder::new().with_epoch_row(Some(5));
+        let deriver = builder
+            .clone()
+            .initialize(true)
+            .with_epoch(Some(epoch))
+            .build()
+            .unwrap();
+        assert_eq!(deriver.epoch(), Some(epoch));
+        assert_eq!(
+            rpi::mock::state().customer_otp[5..7],
+            [0x0123_4567, 0x89ab_cdef]
+        );
+        assert_eq!(read_epoch(5).unwrap(), epoch);
+        // The epoch is read back when building without initialization.
+        let again = builder.clone().build().unwrap();
+        assert_eq!(again.epoch(), Some(epoch));
+        assert_eq!(
+            again.derive_key_vec("info", 32).unwrap(),
+            deriver.derive_key_vec("info", 32).unwrap()
+        );
+        assert_ne!(
+            DeriverBuilder::new()
+                .build()
+                .unwrap()
+                .derive_key_vec("info", 32)
+                .unwrap(),
+            deriver.derive_key_vec("info", 32).unwrap()
+        );
+        // The framing is documented such that it can be reproduced.
+        let fake = builder
+            .clone()
+            .with_fake_secret(&FAKE_SECRET)
+            .build()
+            .unwrap();
+        assert_eq!(fake.epoch(), Some(epoch));
+        assert_eq!(
+            fake.derive_key_vec("info", 32).unwrap(),
+            Deriver::new_fake(None, &FAKE_SECRET)
+                .derive_key_vec(
+                    b"\x00\x00\x00\x0depoch\x01\x23\x45\x67\x89\xab\xcd\xefinfo",
+                    32
+                )
+                .unwrap()
+        );
+        // The epoch is only written when initializing the device secret.
+        rpi::mock::reset(true);
+        rpi::mock::state().customer_otp[5..7].copy_from_slice(&[0, 1]);
+        assert!(builder
+            .clone()
+            .initialize(true)
+            .with_epoch(Some(epoch))
+            .build_or_initialize()
+            .is_err());
+        assert_eq!(rpi::mock::state().private_key, [0; 8]);
+        assert_eq!(
+            builder
+                .clone()
+                .initialize(true)
+                .with_epoch(Some(1))
+                .build_or_initialize()
+                .unwrap()
+                .0
+                .epoch(),
+            Some(1)
+        );
+        // Invalid configurations are refused.
+        assert!(matches!(
+            DeriverBuilder::new().with_epoch(Some(epoch)).build(),
+            Err(BuildError::UnusedEpoch)
+        ));
+        assert!(matches!(
+            DeriverBuilder::new()
+                .initialize(true)
+                .with_epoch(Some(epoch))
+                .build(),
+            Err(BuildError::NoEpochRow)
+        ));
+        assert!(matches!(
+            builder.clone().with_epoch_row(Some(7)).build(),
+            Err(BuildError::InvalidEpochRow { row: 7 })
+        ));
+        assert!(matches!(
+            builder.clone().with_generation_row(Some(6)).build(),
+            Err(BuildError::InvalidGenerationRow { row: 6 })
+        ));
+        rpi::mock::reset(true);
+        assert!(matches!(
+            builder
+                .clone()
+                .with_secret_location(SecretLocation::CustomerOtp)
+                .initialize(true)
+                .with_epoch(Some(epoch))
+                .build(),
+            Err(BuildError::InvalidEpochRow { row: 5 })
+        ));
+        // Rows beyond the customer OTP rows never overflow.
+        let error = builder
+            .clone()
+            .with_epoch_row(Some(usize::MAX))
+            .initialize(true)
+            .with_epoch(Some(epoch))
+            .build()
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            BuildError::InvalidEpochRow { row: usize::MAX }
+        ));
+        assert!(error
+            .to_string()
+            .contains("cannot store the provisioning epoch"));
+        assert!(matches!(
+            builder
+                .with_epoch_row(Some(usize::MAX))
+                .with_generation_row(Some(7))
+                .bump_generation(),
+            Ok(1)
+        ));
+        for row in [7, usize::MAX - 1, usize::MAX] {
+            assert_eq!(
+                read_epoch(row).unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+        }
+    }
+
+    /// Tests using the VCIO device at a custom path using the mocked VCIO device.
+    #[cfg(all(feature = "mock-vcio", target_os = "linux"))]
+    #[test]
+    fn test_mock_vcio_path() {
+        let _guard = rpi::mock::exclusive();
+        rpi::mock::reset(true);
+        let builder = DeriverBuilder::new().initialize(true);
+        let deriver = builder
+            .clone()
+            .with_vcio_path(rpi::vcio::VCIO_PATH.into())
+            .build()
+            .unwrap();
+        assert_eq!(
+            deriver.derive_key_vec("info", 32).unwrap(),
+            builder.build().unwrap().derive_key_vec("info", 32).unwrap()
+        );
+        // The custom path is also used when binding keys to the serial.
+        for builder in [
+            DeriverBuilder::new(),
+            DeriverBuilder::new()
+                .with_fake_secret(&FAKE_SECRET)
+                .bind_to_serial(true),
+        ] {
+            let error = builder
+                .with_vcio_path("/nonexistent/vcio".into())
+                .build()
+                .unwrap_err();
+            assert!(matches!(
+                error,
+                BuildError::Io(error) if error.kind() == io::ErrorKind::NotFound
+            ));
+        }
+    }
+
+    /// Tests that failing builds release the lock on the mocked VCIO device.
+    #[cfg(all(feature = "mock-vcio", target_os = "linux"))]
+    #[test]
+    fn test_mock_lock_released() {
+        let _guard = rpi::mock::exclusive();
+        rpi::mock::reset(true);
+        let vcio = rpi::vcio::Vcio::open().unwrap();
+        assert!(matches!(
+            DeriverBuilder::new().build(),
+            Err(BuildError::Uninitialized)
+        ));
+        drop(vcio.lock_exclusive().unwrap());
+        assert!(matches!(
+            DeriverBuilder::new()
+                .with_generation_row(Some(9))
+                .initialize(true)
+                .build(),
+            Err(BuildError::InvalidGenerationRow { row: 9 })
+        ));
+        drop(vcio.lock_exclusive().unwrap());
+        // Building fails while another handle holds the lock.
+        let lock = vcio.lock_shared().unwrap();
+        let error = DeriverBuilder::new()
+            .initialize(true)
+            .with_lock_retries(0, Duration::ZERO)
+            .build()
+            .unwrap_err();
+        assert!(
+            matches!(error, BuildError::Io(error) if error.kind() == io::ErrorKind::WouldBlock)
+        );
+        drop(lock);
+        assert!(DeriverBuilder::new().initialize(true).build().is_ok());
     }
-}
 
-impl std::fmt::Debug for Deriver {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Deriver").finish_non_exhaustive()
+    /// Tests refusing boards reporting fewer customer OTP rows using the mocked VCIO
+    /// device.
+    #[cfg(all(feature = "mock-vcio", target_os = "linux"))]
+    #[test]
+    fn test_mock_unsupported_layout() {
+        let _guard = rpi::mock::exclusive();
+        rpi::mock::reset(true);
+        rpi::mock::state().customer_otp_rows = 6;
+        let builder = DeriverBuilder::new()
+            .with_secret_location(SecretLocation::CustomerOtp)
+            .initialize(true);
+        let error = builder.clone().build().unwrap_err();
+        assert!(error.to_string().contains("OTP layout"));
+        assert!(matches!(
+            &error,
+            BuildError::Io(error) if error.kind() == io::ErrorKind::Unsupported
+        ));
+        // Nothing has been written.
+        assert_eq!(rpi::mock::state().customer_otp, [0; 8]);
+        assert!(read_customer_otp_rows(4..8).is_err());
+        assert_eq!(read_customer_otp_rows(0..6).unwrap(), [0; 6]);
+        // Restricting the rows to the available ones works.
+        assert!(builder.with_customer_otp_rows(0..6).build().is_ok());
+        assert!(DeriverBuilder::new().initialize(true).build().is_ok());
     }
 }
@@ -1,72 +1,556 @@
 //! Functionality specific to the Raspberry Pi (only available on Linux).
 
-// use std::io;
+use std::{io, ops::Range, thread, time::Duration};
 
-// use self::vcio::Vcio;
-// use crate::{secrets::DeviceSecret, SecretLocation};
+use rand::{CryptoRng, RngCore};
 
+use self::vcio::VcioLock;
+use crate::{
+    secrets::{self, DeviceSecret},
+    SecretLocation,
+};
+
+pub(crate) mod info;
+#[cfg(feature = "mock-vcio")]
+pub(crate) mod mock;
 pub(crate) mod otp;
 pub(crate) mod vcio;
 
-// pub(crate) struct OtpStore {
-//     vcio: Vcio,
-//     location: SecretLocation,
-// }
-
-// impl OtpStore {
-//     pub fn open(location: SecretLocation) -> Result<Self, io::Error> {
-//         Ok(Self {
-//             vcio: Vcio::open()?,
-//             location,
-//         })
-//     }
-
-//     pub fn read(&self) -> Result<DeviceSecret, io::Error> {
-//         match self.location {
-//             SecretLocation::PrivateKey => otp::read_private_key(&self.vcio),
-//             SecretLocation::CustomerOtp => otp::read_customer_otp(&self.vcio),
-//         }
-//     }
-
-//     pub fn write(&mut self, secret: &DeviceSecret) -> Result<DeviceSecret, io::Error> {
-//         self.vcio.lock_exclusive()?;
-//         if *self.read()? != *DeviceSecret::default() {
-//             let _ = self.vcio.unlock();
-//             return Err(io::Error::new(
-//                 io::ErrorKind::Other,
-//                 format!("Device secret has already been written."),
-//             ));
-//         };
-//         let result = match self.location {
-//             SecretLocation::PrivateKey => otp::write_private_key(&self.vcio, &secret),
-//             SecretLocation::CustomerOtp => otp::write_customer_otp(&self.vcio,
-// &secret),         };
-//         let _ = self.vcio.unlock();
-//         result
-//     }
-// }
-
-// pub(crate) struct FakeStore {
-//     secret: Option<DeviceSecret>,
-// }
-
-// impl FakeStore {
-//     pub fn new(secret: Option<DeviceSecret>) -> Self {
-//         Self { secret }
-//     }
-
-//     pub fn read(&self) -> Result<DeviceSecret, io::Error> {
-//         Ok(self.secret.clone().unwrap_or_default())
-//     }
-
-//     pub fn write(&mut self, secret: &DeviceSecret) -> Result<DeviceSecret, io::Error> {
-//         if !self.secret.is_none() {
-//             return Err(io::Error::new(
-//                 io::ErrorKind::Other,
-//                 format!("Device secret has already been written."),
-//             ));
-//         }
-//         self.secret = Some(secret.clone());
-//         self.read()
-//     }
-// }
+/// Checks whether the provided secret has been initialized, i.e., is non-zero.
+pub(crate) fn is_initialized(secret: &DeviceSecret) -> bool {
+    secret.as_slice() != [0; 32].as_slice()
+}
+
+/// The maximal number of attempts to write the device secret.
+const WRITE_ATTEMPTS: usize = 3;
+
+/// Error indicating that the device secret has only been partially written and that the
+/// write cannot be completed.
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error(
+    "Device secret has only been partially written ({non_zero_rows} of {rows} rows are \
+     non-zero)."
+)]
+pub(crate) struct PartialWrite {
+    /// The number of non-zero rows.
+    pub non_zero_rows: usize,
+    /// The number of rows used for the device secret.
+    pub rows: usize,
+}
+
+/// Extracts the [`PartialWrite`] error in case the error has been caused by it.
+pub(crate) fn as_partial_write(error: &io::Error) -> Option<PartialWrite> {
+    error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<PartialWrite>())
+        .copied()
+}
+
+/// Checks whether writing the intended value over the written value yields the intended
+/// value, i.e., no bit is set which is not also set in the intended value.
+fn can_complete(written: &[u8], intended: &[u8]) -> bool {
+    written
+        .iter()
+        .zip(intended)
+        .all(|(written, intended)| written & !intended == 0)
+}
+
+/// Counts the non-zero rows among the first `rows` rows of the provided secret.
+pub(crate) fn count_non_zero_rows(secret: &DeviceSecret, rows: usize) -> usize {
+    secret[..4 * rows]
+        .chunks(4)
+        .filter(|row| row.iter().any(|byte| *byte != 0))
+        .count()
+}
+
+/// The rows of the device secret holding device-specific random bits.
+///
+/// With all OTP rows, the lower four rows may hold a group secret instead. A group secret
+/// is not random and the rows are thus excluded.
+pub(crate) fn device_specific_rows(rows: usize) -> Range<usize> {
+    if rows < otp::OTP_ROWS {
+        0..rows
+    } else {
+        4..rows
+    }
+}
+
+/// Decodes the generation stored in the value of a customer OTP row.
+///
+/// The generation is encoded in unary as the number of consecutive set bits starting
+/// with the least significant bit. Bumping the generation sets the next bit.
+pub(crate) fn decode_generation(value: u32) -> u32 {
+    value.trailing_ones()
+}
+
+/// Retries the provided locking operation while it fails with
+/// [`io::ErrorKind::WouldBlock`], at most `retries` times and sleeping `delay` in
+/// between.
+pub(crate) fn retry_lock<T>(
+    retries: u32,
+    delay: Duration,
+    mut lock: impl FnMut() -> Result<T, io::Error>,
+) -> Result<T, io::Error> {
+    let mut attempt = 0;
+    loop {
+        match lock() {
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock && attempt < retries => {
+                attempt += 1;
+                thread::sleep(delay);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Storage of the device secret in the OTP registers.
+///
+/// The store holds a lock on the VCIO device which is released when the store is dropped.
+#[derive(Debug)]
+pub(crate) struct OtpStore<'vcio> {
+    /// The lock on the VCIO device.
+    lock: VcioLock<'vcio>,
+    /// The location of the device secret.
+    location: SecretLocation,
+    /// The customer OTP rows used for the device secret.
+    customer_otp_rows: Range<usize>,
+}
+
+impl<'vcio> OtpStore<'vcio> {
+    /// Creates a store for the given location accessing the VCIO device under the given
+    /// lock.
+    pub fn new(lock: VcioLock<'vcio>, location: SecretLocation) -> Self {
+        Self {
+            lock,
+            location,
+            customer_otp_rows: 0..otp::OTP_ROWS,
+        }
+    }
+
+    /// Restricts the customer OTP rows used for the device secret.
+    ///
+    /// The rows are relative to the first customer OTP row (row 36).
+    pub fn set_customer_otp_rows(&mut self, rows: Range<usize>) {
+        self.customer_otp_rows = rows;
+    }
+
+    /// The location of the device secret.
+    ///
+    /// This is [`SecretLocation::Auto`] until
+    /// [`resolve_location`][Self::resolve_location] has been called.
+    pub fn location(&self) -> SecretLocation {
+        self.location
+    }
+
+    /// Resolves [`SecretLocation::Auto`] to the location actually used.
+    ///
+    /// The private key takes precedence if it has been initialized, followed by the
+    /// customer OTP. If neither has been initialized, the private key is used if the
+    /// firmware supports it. A lock should be held while calling this method.
+    pub fn resolve_location(&mut self) -> Result<(), io::Error> {
+        if self.location != SecretLocation::Auto {
+            return Ok(());
+        }
+        let private_key = match otp::read_private_key(&self.lock) {
+            Ok(secret) => Some(secret),
+            Err(error) if vcio::is_unsuccessful_response(&error) => None,
+            Err(error) => return Err(error),
+        };
+        self.location = if matches!(&private_key, Some(secret) if is_initialized(secret)) {
+            SecretLocation::PrivateKey
+        } else if is_initialized(&otp::read_customer_otp_rows(
+            &self.lock,
+            self.customer_otp_rows.clone(),
+        )?) {
+            SecretLocation::CustomerOtp
+        } else if private_key.is_some() {
+            SecretLocation::PrivateKey
+        } else {
+            SecretLocation::CustomerOtp
+        };
+        Ok(())
+    }
+
+    /// The number of OTP rows used for the device secret.
+    pub fn rows(&self) -> usize {
+        self.rows_at(self.location)
+    }
+
+    /// The number of OTP rows used for a secret at the given location.
+    pub fn rows_at(&self, location: SecretLocation) -> usize {
+        match location {
+            SecretLocation::PrivateKey => otp::OTP_ROWS,
+            SecretLocation::CustomerOtp => self.customer_otp_rows.len(),
+            SecretLocation::Auto => unreachable!("The location must be resolved first."),
+        }
+    }
+
+    /// Indicates whether the device secret is stored in the given customer OTP row.
+    pub fn uses_customer_otp_row(&self, row: usize) -> bool {
+        self.location == SecretLocation::CustomerOtp && self.customer_otp_rows.contains(&row)
+    }
+
+    /// Reads the generation stored in the given customer OTP row.
+    pub fn read_generation(&self, row: usize) -> Result<u32, io::Error> {
+        Ok(decode_generation(otp::read_customer_otp_row(
+            &self.lock, row,
+        )?))
+    }
+
+    /// Irreversibly bumps the generation stored in the given customer OTP row by setting
+    /// the next bit and returns the new generation.
+    ///
+    /// The caller must ensure that the row is not used for the device secret, that the
+    /// generation is below [`MAX_GENERATION`][crate::MAX_GENERATION], and that an
+    /// exclusive lock is held.
+    pub fn bump_generation(&self, row: usize) -> Result<u32, io::Error> {
+        let generation = self.read_generation(row)?;
+        debug_assert!(generation < crate::MAX_GENERATION);
+        let written = otp::write_customer_otp_row(&self.lock, row, 1 << generation)?;
+        let bumped = decode_generation(written);
+        if bumped != generation + 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "Unable to bump generation {} in customer OTP row {} (read back {:#010x}).",
+                    generation, row, written
+                ),
+            ));
+        }
+        Ok(bumped)
+    }
+
+    /// Reads the provisioning epoch stored in the given customer OTP row and the next
+    /// one.
+    pub fn read_epoch(&self, row: usize) -> Result<u64, io::Error> {
+        let rows = otp::read_row_range(&self.lock, row, 2)?;
+        Ok(u64::from(rows[0]) << 32 | u64::from(rows[1]))
+    }
+
+    /// Irreversibly writes the provisioning epoch to the given customer OTP row and the
+    /// next one.
+    ///
+    /// Writing the epoch which has already been written is idempotent. Refuses to write
+    /// a different epoch in case an epoch has already been written. An exclusive lock
+    /// should be held while calling this method.
+    pub fn write_epoch(&self, row: usize, epoch: u64) -> Result<(), io::Error> {
+        let written = self.read_epoch(row)?;
+        if written == epoch {
+            return Ok(());
+        }
+        if written != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("A different epoch ({}) has already been written.", written),
+            ));
+        }
+        otp::write_row_range(&self.lock, row, &[(epoch >> 32) as u32, epoch as u32])?;
+        let written = self.read_epoch(row)?;
+        if written != epoch {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "Unable to write epoch {} to customer OTP rows {} and {} (read back {}).",
+                    epoch,
+                    row,
+                    row + 1,
+                    written
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reads the device secret.
+    pub fn read(&self) -> Result<DeviceSecret, io::Error> {
+        self.read_at(self.location)
+    }
+
+    /// Reads the secret stored at the other location, i.e., the location not used for
+    /// the device secret.
+    ///
+    /// Returns [`None`] in case the other location is the private key and the firmware
+    /// does not support it.
+    pub fn read_other(&self) -> Result<Option<(SecretLocation, DeviceSecret)>, io::Error> {
+        let location = match self.location {
+            SecretLocation::PrivateKey => SecretLocation::CustomerOtp,
+            SecretLocation::CustomerOtp => SecretLocation::PrivateKey,
+            SecretLocation::Auto => unreachable!("The location must be resolved first."),
+        };
+        match self.read_at(location) {
+            Ok(secret) => Ok(Some((location, secret))),
+            Err(error)
+                if location == SecretLocation::PrivateKey
+                    && vcio::is_unsuccessful_response(&error) =>
+            {
+                Ok(None)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Reads the secret stored at the given location.
+    fn read_at(&self, location: SecretLocation) -> Result<DeviceSecret, io::Error> {
+        match location {
+            SecretLocation::PrivateKey => otp::read_private_key(&self.lock),
+            SecretLocation::CustomerOtp => {
+                otp::read_customer_otp_rows(&self.lock, self.customer_otp_rows.clone())
+            }
+            SecretLocation::Auto => unreachable!("The location must be resolved first."),
+        }
+    }
+
+    /// Irreversibly writes the device secret.
+    ///
+    /// The store must hold an exclusive lock. The secret is re-read before writing and
+    /// this method refuses to overwrite a secret which has already been written.
+    ///
+    /// OTP bits are write-once: they can be set but never be cleared again. Writing a
+    /// value sets all of its bits, hence, writing the same value again is idempotent
+    /// while writing a different value yields the bitwise OR of both values. After
+    /// writing, the secret is read back. In case the write has been interrupted, e.g.,
+    /// because of a failing request, it is completed by writing the same secret again,
+    /// provided that all bits set so far are also set in the secret. Otherwise, fails
+    /// with [`PartialWrite`].
+    pub fn write(&mut self, secret: &DeviceSecret) -> Result<DeviceSecret, io::Error> {
+        if !self.lock.is_exclusive() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Writing the device secret requires an exclusive lock.",
+            ));
+        }
+        if is_initialized(&self.read()?) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Device secret has already been written.",
+            ));
+        }
+        let rows = self.rows();
+        let len = 4 * rows;
+        let mut last_error = None;
+        for attempt in 1..=WRITE_ATTEMPTS {
+            if attempt > 1 {
+                log!(
+                    warn,
+                    "Completing interrupted write of device secret (attempt {} of {}).",
+                    attempt,
+                    WRITE_ATTEMPTS
+                );
+            }
+            if let Err(error) = self.write_rows(secret) {
+                last_error = Some(error);
+            }
+            let written = self.read()?;
+            if written[..len] == secret[..len] {
+                return Ok(written);
+            }
+            if !is_initialized(&written) {
+                if let Some(error) = last_error.take() {
+                    // Nothing has been written, hence, the write can be safely retried.
+                    return Err(error);
+                }
+            }
+            if attempt == WRITE_ATTEMPTS || !can_complete(&written[..len], &secret[..len]) {
+                let partial = PartialWrite {
+                    non_zero_rows: count_non_zero_rows(&written, rows),
+                    rows,
+                };
+                return Err(io::Error::new(io::ErrorKind::Other, partial));
+            }
+        }
+        unreachable!("The last attempt always returns.")
+    }
+
+    /// Writes the device secret to the OTP rows without any checks.
+    fn write_rows(&self, secret: &DeviceSecret) -> Result<DeviceSecret, io::Error> {
+        match self.location {
+            SecretLocation::PrivateKey => otp::write_private_key(&self.lock, secret),
+            SecretLocation::CustomerOtp => {
+                otp::write_customer_otp_rows(&self.lock, self.customer_otp_rows.clone(), secret)
+            }
+            SecretLocation::Auto => unreachable!("The location must be resolved first."),
+        }
+    }
+
+    /// Generates a new device secret, overlays the optional group secret onto its
+    /// upper 128-bits, and irreversibly writes it.
+    pub fn initialize(
+        &mut self,
+        group_secret: Option<&[u8; 16]>,
+    ) -> Result<DeviceSecret, io::Error> {
+        self.initialize_with_rng(group_secret, &mut rand::thread_rng())
+    }
+
+    /// Same as [`initialize`][Self::initialize] but generates the device secret with the
+    /// provided random number generator.
+    pub fn initialize_with_rng<R: RngCore + CryptoRng + ?Sized>(
+        &mut self,
+        group_secret: Option<&[u8; 16]>,
+        rng: &mut R,
+    ) -> Result<DeviceSecret, io::Error> {
+        if group_secret.is_some() && self.rows() < otp::OTP_ROWS {
+            // The group secret would occupy all device-specific bits.
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A group secret requires all OTP rows for the device secret.",
+            ));
+        }
+        self.write(&secrets::generate_device_secret_with_group(
+            group_secret,
+            rng,
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "mock-vcio")]
+    use crate::rpi::vcio::Vcio;
+
+    /// Tests counting the non-zero rows of a secret.
+    #[test]
+    pub fn test_count_non_zero_rows() {
+        let mut secret = DeviceSecret::new();
+        assert_eq!(count_non_zero_rows(&secret, 8), 0);
+        secret[3] = 1;
+        secret[31] = 1;
+        assert_eq!(count_non_zero_rows(&secret, 8), 2);
+        assert_eq!(count_non_zero_rows(&secret, 4), 1);
+        secret.copy_from_slice(&[0xFF; 32]);
+        assert_eq!(count_non_zero_rows(&secret, 8), 8);
+    }
+
+    /// Tests that locking is retried only when it would block.
+    #[test]
+    pub fn test_retry_lock() {
+        let mut attempts = 0;
+        let result: Result<(), _> = retry_lock(3, Duration::ZERO, || {
+            attempts += 1;
+            Err(io::ErrorKind::WouldBlock.into())
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(attempts, 4);
+
+        let mut attempts = 0;
+        let result: Result<(), _> = retry_lock(3, Duration::ZERO, || {
+            attempts += 1;
+            if attempts < 3 {
+                Err(io::ErrorKind::WouldBlock.into())
+            } else {
+                Ok(())
+            }
+        });
+        assert!(result.is_ok());
+        assert_eq!(attempts, 3);
+
+        let mut attempts = 0;
+        let result: Result<(), _> = retry_lock(3, Duration::ZERO, || {
+            attempts += 1;
+            Err(io::ErrorKind::PermissionDenied.into())
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(attempts, 1);
+    }
+
+    /// Tests reading and initializing the device secret using the mocked VCIO device.
+    #[cfg(feature = "mock-vcio")]
+    #[test]
+    pub fn test_mock_store() {
+        let _guard = mock::exclusive();
+        mock::reset(true);
+        let vcio = Vcio::open().unwrap();
+        let mut store = OtpStore::new(vcio.lock_exclusive().unwrap(), SecretLocation::CustomerOtp);
+        store.set_customer_otp_rows(2..6);
+        assert!(!is_initialized(&store.read().unwrap()));
+        // A group secret would leave no device-specific bits.
+        assert_eq!(
+            store.initialize(Some(&[0xAB; 16])).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(mock::state().customer_otp, [0; 8]);
+        let secret = store.initialize(None).unwrap();
+        assert_eq!(store.read().unwrap().as_slice(), secret.as_slice());
+        assert!(count_non_zero_rows(&secret, 4) > 0);
+        assert_eq!(&secret[16..], &[0; 16]);
+        assert_eq!(
+            store.initialize(None).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        let state = mock::state();
+        assert_eq!(state.customer_otp[..2], [0, 0]);
+        assert_eq!(state.customer_otp[6..], [0, 0]);
+        assert_eq!(state.private_key, [0; 8]);
+        drop(state);
+        // With all rows, the group secret is followed by device-specific bits.
+        mock::reset(true);
+        store.set_customer_otp_rows(0..8);
+        let secret = store.initialize(Some(&[0xAB; 16])).unwrap();
+        assert_eq!(&secret[..16], &[0xAB; 16]);
+        assert_ne!(&secret[16..], &[0; 16]);
+        assert_eq!(mock::state().customer_otp[..4], [0xABABABAB; 4]);
+        // Writing requires an exclusive lock.
+        mock::reset(true);
+        drop(store);
+        let mut store = OtpStore::new(vcio.lock_shared().unwrap(), SecretLocation::PrivateKey);
+        assert!(store.initialize(None).is_err());
+        assert_eq!(mock::state().private_key, [0; 8]);
+    }
+
+    /// Tests the detection of the secret location using the mocked VCIO device.
+    #[cfg(feature = "mock-vcio")]
+    #[test]
+    pub fn test_mock_resolve_location() {
+        let _guard = mock::exclusive();
+        let resolve = || {
+            let vcio = Vcio::open().unwrap();
+            let mut store = OtpStore::new(vcio.lock_shared().unwrap(), SecretLocation::Auto);
+            store.resolve_location().unwrap();
+            store.location()
+        };
+        mock::reset(true);
+        assert_eq!(resolve(), SecretLocation::PrivateKey);
+        mock::state().customer_otp = [1; 8];
+        assert_eq!(resolve(), SecretLocation::CustomerOtp);
+        mock::state().private_key = [1; 8];
+        assert_eq!(resolve(), SecretLocation::PrivateKey);
+        mock::reset(false);
+        assert_eq!(resolve(), SecretLocation::CustomerOtp);
+    }
+
+    /// Tests detecting whether an interrupted write can be completed.
+    #[test]
+    fn test_can_complete() {
+        assert!(can_complete(&[0, 0], &[0xab, 0xcd]));
+        assert!(can_complete(&[0xab, 0x0c], &[0xab, 0xcd]));
+        assert!(!can_complete(&[0xab, 0x02], &[0xab, 0xcd]));
+    }
+
+    /// Tests completing interrupted writes using the mocked VCIO device.
+    #[cfg(feature = "mock-vcio")]
+    #[test]
+    fn test_mock_interrupted_write() {
+        let _guard = mock::exclusive();
+        mock::reset(true);
+        mock::state().interrupted_writes = 2;
+        let vcio = Vcio::open().unwrap();
+        let mut store = OtpStore::new(vcio.lock_exclusive().unwrap(), SecretLocation::PrivateKey);
+        let secret = store.initialize(None).unwrap();
+        assert_eq!(mock::state().interrupted_writes, 0);
+        assert_eq!(store.read().unwrap().as_slice(), secret.as_slice());
+
+        mock::reset(true);
+        mock::state().interrupted_writes = WRITE_ATTEMPTS as u32;
+        let error = store.initialize(None).unwrap_err();
+        assert!(matches!(
+            as_partial_write(&error),
+            Some(PartialWrite { rows: 8, .. })
+        ));
+
+        mock::reset(true);
+        mock::state().corrupted_writes = 1;
+        let error = store.initialize(None).unwrap_err();
+        assert!(as_partial_write(&error).is_some());
+        assert_eq!(mock::state().private_key[0], u32::MAX);
+    }
+}
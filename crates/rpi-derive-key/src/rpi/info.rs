@@ -0,0 +1,83 @@
+//! Helper functions for obtaining information about the board.
+
+use std::io;
+
+use crate::rpi::vcio::{self, Vcio};
+
+/// Request tags for obtaining information about the board.
+#[derive(Debug, Clone, Copy)]
+#[repr(u32)]
+#[allow(clippy::enum_variant_names)] // Mirrors the names of the tags of the firmware.
+enum Tag {
+    /// Get the firmware revision.
+    GetFirmwareRevision = 0x00000001,
+    /// Get the board revision.
+    GetBoardRevision = 0x00010002,
+    /// Get the board serial.
+    GetBoardSerial = 0x00010004,
+}
+
+/// Encodes a request for the property interface with a response value of `N` words.
+fn encode_request<const N: usize>(tag: Tag) -> Vec<u32> {
+    let mut buffer = vec![0; 6 + N];
+    buffer[0] = (buffer.len() * 4) as u32; // Size of the buffer in bytes.
+    buffer[1] = 0; // Request code (process request).
+    buffer[2] = tag as u32; // The request tag.
+    buffer[3] = (N * 4) as u32; // Size of the value buffer in bytes.
+    buffer[4] = 0; // Tag request code.
+                   // The remaining words are the value buffer followed by the end tag.
+    buffer
+}
+
+/// Sends a request to the property interface and returns the `N` words of the response.
+fn send_request<const N: usize>(vcio: &Vcio, tag: Tag) -> Result<[u32; N], io::Error> {
+    let mut buffer = encode_request::<N>(tag);
+    unsafe {
+        // SAFETY: The buffer is valid according to the property interface.
+        vcio.ioctl_property(&mut buffer)?;
+    };
+    vcio::check_response(&buffer)?;
+    let mut value = [0; N];
+    value.copy_from_slice(&buffer[5..5 + N]);
+    Ok(value)
+}
+
+/// Reads the 64-bit serial number of the board.
+pub(crate) fn get_board_serial(vcio: &Vcio) -> Result<u64, io::Error> {
+    let [low, high] = send_request::<2>(vcio, Tag::GetBoardSerial)?;
+    Ok((u64::from(high) << 32) | u64::from(low))
+}
+
+/// Reads the revision of the board.
+pub(crate) fn get_board_revision(vcio: &Vcio) -> Result<u32, io::Error> {
+    let [revision] = send_request::<1>(vcio, Tag::GetBoardRevision)?;
+    Ok(revision)
+}
+
+/// Reads the revision of the firmware (the time of its build as UNIX timestamp).
+pub(crate) fn get_firmware_revision(vcio: &Vcio) -> Result<u32, io::Error> {
+    let [revision] = send_request::<1>(vcio, Tag::GetFirmwareRevision)?;
+    Ok(revision)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests the encoding of requests.
+    #[test]
+    pub fn test_request_encoding() {
+        assert_eq!(
+            encode_request::<2>(Tag::GetBoardSerial),
+            [32, 0, 0x00010004, 8, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            encode_request::<1>(Tag::GetBoardRevision),
+            [28, 0, 0x00010002, 4, 0, 0, 0]
+        );
+        assert_eq!(
+            encode_request::<1>(Tag::GetFirmwareRevision),
+            [28, 0, 0x00000001, 4, 0, 0, 0]
+        );
+    }
+}
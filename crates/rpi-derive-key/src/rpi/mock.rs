@@ -0,0 +1,167 @@
+//! In-memory mock of the VCIO property interface (enabled with the `mock-vcio` feature).
+//!
+//! The mock simulates the customer-programmable and private key OTP registers as well as
+//! the board information such that the OTP code paths can be tested without hardware.
+//! Like real OTP memory, bits of the simulated registers can only be set but never be
+//! cleared. The state is shared by the entire process.
+
+use std::{
+    io,
+    sync::{Mutex, MutexGuard},
+};
+
+use nix::libc::c_int;
+
+use super::{otp::OTP_ROWS, vcio::RESPONSE_SUCCESS};
+
+/// The response code of the property interface indicating an error.
+const RESPONSE_ERROR: u32 = 0x80000001;
+
+/// The state of the simulated OTP registers and board.
+#[derive(Debug)]
+pub(crate) struct MockState {
+    /// The customer-programmable OTP rows.
+    pub customer_otp: [u32; OTP_ROWS],
+    /// The number of customer-programmable OTP rows reported by the simulated firmware.
+    pub customer_otp_rows: usize,
+    /// The private key OTP rows.
+    pub private_key: [u32; OTP_ROWS],
+    /// Indicates whether the simulated firmware supports the private key.
+    pub supports_private_key: bool,
+    /// The serial number of the board.
+    pub board_serial: u64,
+    /// The revision code of the board.
+    pub board_revision: u32,
+    /// The revision of the firmware.
+    pub firmware_revision: u32,
+    /// The number of subsequent writes which are interrupted after half of the rows.
+    pub interrupted_writes: u32,
+    /// The number of subsequent writes which additionally set all bits of the first row.
+    pub corrupted_writes: u32,
+}
+
+impl MockState {
+    /// The state of a new board with a recent firmware.
+    const fn new() -> Self {
+        Self {
+            customer_otp: [0; OTP_ROWS],
+            customer_otp_rows: OTP_ROWS,
+            private_key: [0; OTP_ROWS],
+            supports_private_key: true,
+            board_serial: 0x0000_0000_1234_abcd,
+            board_revision: 0x00c0_3111,
+            firmware_revision: 0x6400_0000,
+            interrupted_writes: 0,
+            corrupted_writes: 0,
+        }
+    }
+}
+
+/// The shared state of the mock.
+static STATE: Mutex<MockState> = Mutex::new(MockState::new());
+
+/// Serializes tests using the mock.
+static EXCLUSIVE: Mutex<()> = Mutex::new(());
+
+/// Provides access to the state of the mock.
+pub(crate) fn state() -> MutexGuard<'static, MockState> {
+    STATE.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+/// Obtains exclusive usage of the mock for the lifetime of the returned guard.
+///
+/// Tests using the mock must hold this guard as they run in parallel.
+#[allow(dead_code)] // Only used in tests.
+pub(crate) fn exclusive() -> MutexGuard<'static, ()> {
+    EXCLUSIVE.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+/// Resets the mock to a new board with or without support for the private key.
+///
+/// Without support for the private key, the firmware revision predates the private key.
+#[allow(dead_code)] // Only used in tests.
+pub(crate) fn reset(supports_private_key: bool) {
+    let mut state = MockState::new();
+    state.supports_private_key = supports_private_key;
+    if !supports_private_key {
+        state.firmware_revision = 0x5e00_0000;
+    }
+    *self::state() = state;
+}
+
+/// Processes a request to the property interface like the firmware would.
+pub(crate) fn ioctl_property(buffer: &mut [u32]) -> Result<c_int, io::Error> {
+    let mut state = state();
+    let tag = buffer[2];
+    let success = match tag {
+        // Get the firmware revision.
+        0x00000001 => {
+            buffer[5] = state.firmware_revision;
+            true
+        }
+        // Get the board revision.
+        0x00010002 => {
+            buffer[5] = state.board_revision;
+            true
+        }
+        // Get the board serial.
+        0x00010004 => {
+            buffer[5] = state.board_serial as u32;
+            buffer[6] = (state.board_serial >> 32) as u32;
+            true
+        }
+        // Get or set the customer OTP values or the private key.
+        0x00030021 | 0x00038021 | 0x00030081 | 0x00038081 => {
+            let is_set = tag & 0x00008000 != 0;
+            let start = buffer[5] as usize;
+            let count = buffer[6] as usize;
+            let mut written = count;
+            let mut corrupted = false;
+            if is_set && state.interrupted_writes > 0 {
+                state.interrupted_writes -= 1;
+                written = count / 2;
+            }
+            if is_set && state.corrupted_writes > 0 {
+                state.corrupted_writes -= 1;
+                corrupted = true;
+            }
+            let rows = if tag & 0xFF == 0x81 {
+                if !state.supports_private_key {
+                    buffer[1] = RESPONSE_ERROR;
+                    return Ok(0);
+                }
+                &mut state.private_key[..]
+            } else {
+                // Like firmware with fewer rows, only process the available rows.
+                let available = state.customer_otp_rows;
+                let count = count.min(available.saturating_sub(start));
+                buffer[6] = count as u32;
+                &mut state.customer_otp[..available]
+            };
+            let count = buffer[6] as usize;
+            if start + count > rows.len() {
+                false
+            } else {
+                for idx in 0..count {
+                    if is_set && idx < written {
+                        // OTP bits can only be set.
+                        rows[start + idx] |= buffer[7 + idx];
+                    }
+                    if corrupted && idx == 0 {
+                        rows[start] = u32::MAX;
+                    }
+                    buffer[7 + idx] = rows[start + idx];
+                }
+                true
+            }
+        }
+        _ => false,
+    };
+    if success {
+        buffer[1] = RESPONSE_SUCCESS;
+        buffer[4] = RESPONSE_SUCCESS | buffer[3];
+    } else {
+        buffer[1] = RESPONSE_ERROR;
+    }
+    Ok(0)
+}
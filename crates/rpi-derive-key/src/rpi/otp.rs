@@ -1,19 +1,78 @@
 //! Helper functions for accessing the _one-time programmable_ (OTP) device secret.
 
-use std::io;
+use std::{io, ops::Range};
 
 use crate::{
-    rpi::vcio::Vcio,
+    rpi::vcio::{self, Vcio},
     secrets::{DeviceSecret, Secret},
 };
 
 /// Copies bytes from an `u32` buffer as used by the property interface to a byte slice.
-fn copy_bytes(src: &[u32], mut dst: &mut [u8]) {
-    // This solution seams a bit ugly. Is there a better one (without unsafe)?
-    for word in src {
-        dst[..4].copy_from_slice(&word.to_be_bytes());
-        dst = &mut dst[4..];
+///
+/// Fails with [`io::ErrorKind::InvalidInput`] in case the byte slice is not exactly four
+/// times as long as the `u32` buffer.
+fn copy_bytes(src: &[u32], dst: &mut [u8]) -> Result<(), io::Error> {
+    if dst.len() != 4 * src.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Unable to copy {} words into {} bytes.",
+                src.len(),
+                dst.len()
+            ),
+        ));
+    }
+    for (word, bytes) in src.iter().zip(dst.chunks_exact_mut(4)) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    Ok(())
+}
+
+/// The number of OTP rows used for storing the device secret.
+///
+/// All Raspberry Pi models supported by the firmware's OTP interface provide eight
+/// customer OTP rows (rows 36 to 43) and eight private key rows. As the firmware echoes
+/// the rows it has processed, responses are checked against this assumption (see
+/// [`UnsupportedLayout`]) such that a different layout never goes unnoticed.
+pub(crate) const OTP_ROWS: usize = 8;
+
+/// Error indicating that the firmware processed fewer OTP rows than requested.
+///
+/// This indicates a board with an OTP layout different from the eight rows assumed.
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error(
+    "The firmware reports {available} OTP rows starting at row {start} but {requested} rows \
+     are required. The OTP layout of this board is not supported."
+)]
+pub(crate) struct UnsupportedLayout {
+    /// The row the request starts at.
+    pub start: usize,
+    /// The number of rows processed by the firmware.
+    pub available: usize,
+    /// The number of rows requested.
+    pub requested: usize,
+}
+
+/// Checks that the firmware processed all OTP rows of the request in `buffer`.
+fn check_layout(buffer: &[u32], rows: Range<usize>) -> Result<(), io::Error> {
+    // The tag response code carries the length of the response value in bytes.
+    let len = (buffer[4] & !vcio::RESPONSE_SUCCESS) as usize;
+    let available = if buffer[4] & vcio::RESPONSE_SUCCESS == 0 || buffer[5] as usize != rows.start {
+        0
+    } else {
+        (buffer[6] as usize).min(len.saturating_sub(8) / 4)
+    };
+    if available < rows.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            UnsupportedLayout {
+                start: rows.start,
+                available,
+                requested: rows.len(),
+            },
+        ));
     }
+    Ok(())
 }
 
 /// Request tags for accessing OTP values.
@@ -33,18 +92,30 @@ enum Tag {
 /// Encodes a request for the property interface of the VCIO device.
 ///
 /// This function only works for customer-programmable and private key OTP requests.
+/// Only the given `rows` are read or written and the words of the value are mapped to
+/// these rows in order.
 ///
 /// It uses [`Secret`] to protect the device secret.
-fn encode_request(tag: Tag, value: Option<&[u8; 32]>) -> Secret<[u32; 16]> {
-    let mut buffer = Secret::<[u32; 16]>::default();
+fn encode_request(
+    tag: Tag,
+    rows: Range<usize>,
+    value: Option<&[u8; 32]>,
+) -> Result<Secret<[u32; 16]>, io::Error> {
+    if rows.is_empty() || rows.end > OTP_ROWS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid OTP rows {:?}.", rows),
+        ));
+    }
+    let mut buffer = Secret::<[u32; 16]>::try_new()?;
     *buffer = [
         16 * 4,     // Size of the buffer in bytes.
         0,          // Request code (process request).
         tag as u32, // The request tag.
         8 + 32,     // Size of the value buffer in bytes.
         0,          // Tag request code.
-        0,          // Start reading/writing at row 0.
-        8,          // Read/write all 8 rows.
+        0,          // Row to start reading/writing at.
+        0,          // Number of rows to read/write.
         0,          // 1. [u8; 4]
         0,          // 2. [u8; 4]
         0,          // 3. [u8; 4]
@@ -55,67 +126,165 @@ fn encode_request(tag: Tag, value: Option<&[u8; 32]>) -> Secret<[u32; 16]> {
         0,          // 8. [u8; 4]
         0,          // End tag.
     ];
+    buffer[5] = rows.start as u32;
+    buffer[6] = rows.len() as u32;
     if let Some(value) = value {
-        // This solution seams a bit ugly. Is there a better one (without unsafe)?
-        for (idx, word) in value.chunks(4).enumerate() {
-            buffer[7 + idx] = u32::from_be_bytes(word.try_into().unwrap());
+        let words = buffer[7..7 + rows.len()].iter_mut();
+        for (word, bytes) in words.zip(value.chunks_exact(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().unwrap());
         }
     }
-    buffer
+    Ok(buffer)
 }
 
 /// Sends a request to the property interface of the VCIO device and returns the response.
+///
+/// The bytes of the returned device secret not backed by `rows` are zero.
 fn send_request(
     vcio: &Vcio,
     tag: Tag,
+    rows: Range<usize>,
     value: Option<&[u8; 32]>,
 ) -> Result<DeviceSecret, io::Error> {
-    let mut buffer = encode_request(tag, value);
+    let count = rows.len();
+    let mut buffer = encode_request(tag, rows.clone(), value)?;
     unsafe {
         // SAFETY: The buffer is valid according to the property interface.
         vcio.ioctl_property(buffer.as_mut_slice())?;
     };
-    if buffer[1] != 0x80000000 {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!(
-                "Request to VCIO property interface unsuccessful (0x{:08X}).",
-                buffer[1]
-            ),
-        ));
-    }
+    vcio::check_response(buffer.as_slice())?;
+    check_layout(buffer.as_slice(), rows)?;
     // Extract the returned device secret.
-    let mut value = DeviceSecret::new();
-    copy_bytes(&buffer[7..15], value.as_mut_slice());
+    let mut value = DeviceSecret::try_new()?;
+    copy_bytes(&buffer[7..7 + count], &mut value[..4 * count])?;
     Ok(value)
 }
 
 /// Reads the device secret from the customer-programmable OTP registers (rows 36 to 43).
 pub(crate) fn read_customer_otp(vcio: &Vcio) -> Result<DeviceSecret, io::Error> {
-    send_request(vcio, Tag::GetCustomerOtp, None)
+    read_customer_otp_rows(vcio, 0..OTP_ROWS)
+}
+
+/// Reads the device secret from the given customer-programmable OTP rows (relative to
+/// row 36).
+pub(crate) fn read_customer_otp_rows(
+    vcio: &Vcio,
+    rows: Range<usize>,
+) -> Result<DeviceSecret, io::Error> {
+    log!(debug, "Reading customer OTP rows {:?}.", rows);
+    send_request(vcio, Tag::GetCustomerOtp, rows, None)
+}
+
+/// Writes the device secret to the given customer-programmable OTP rows (relative to
+/// row 36) leaving all other rows untouched.
+///
+/// ⚠️ This operation is irreversible.
+pub(crate) fn write_customer_otp_rows(
+    vcio: &Vcio,
+    rows: Range<usize>,
+    value: &[u8; 32],
+) -> Result<DeviceSecret, io::Error> {
+    log!(
+        warn,
+        "Irreversibly writing device secret to customer OTP rows {:?}.",
+        rows
+    );
+    send_request(vcio, Tag::SetCustomerOtp, rows, Some(value))
+}
+
+/// Reads the values of `count` customer-programmable OTP rows starting at `start`
+/// (relative to row 36), e.g., for debugging the OTP layout.
+///
+/// Only the customer OTP region is accessible as the request for reading it is used.
+/// Hence, this function can never be pointed at the private key region. ⚠️ Note that the
+/// customer OTP rows store the device secret when using
+/// [`SecretLocation::CustomerOtp`][crate::SecretLocation::CustomerOtp] and the values
+/// must be treated as secret in this case.
+pub(crate) fn read_row_range(
+    vcio: &Vcio,
+    start: usize,
+    count: usize,
+) -> Result<Vec<u32>, io::Error> {
+    log!(
+        debug,
+        "Reading {} customer OTP rows starting at row {}.",
+        count,
+        start
+    );
+    let value = send_request(vcio, Tag::GetCustomerOtp, start..start + count, None)?;
+    Ok(value[..4 * count]
+        .chunks(4)
+        .map(|row| u32::from_be_bytes(row.try_into().unwrap()))
+        .collect())
+}
+
+/// Reads the value of a single customer-programmable OTP row (relative to row 36).
+pub(crate) fn read_customer_otp_row(vcio: &Vcio, row: usize) -> Result<u32, io::Error> {
+    Ok(read_row_range(vcio, row, 1)?[0])
 }
 
-/// Writes the device secret to the customer-programmable OTP registers (rows 36 to 43).
+/// Sets the bits of the given values in the customer-programmable OTP rows starting at
+/// `start` (relative to row 36) and returns the resulting values of the rows.
 ///
 /// ⚠️ This operation is irreversible.
-pub(crate) fn write_customer_otp(vcio: &Vcio, value: &[u8; 32]) -> Result<DeviceSecret, io::Error> {
-    send_request(vcio, Tag::SetCustomerOtp, Some(value))
+pub(crate) fn write_row_range(
+    vcio: &Vcio,
+    start: usize,
+    values: &[u32],
+) -> Result<Vec<u32>, io::Error> {
+    log!(
+        warn,
+        "Irreversibly writing {:08x?} to customer OTP rows starting at row {}.",
+        values,
+        start
+    );
+    let mut buffer = [0; 32];
+    for (chunk, value) in buffer.chunks_mut(4).zip(values) {
+        chunk.copy_from_slice(&value.to_be_bytes());
+    }
+    let written = send_request(
+        vcio,
+        Tag::SetCustomerOtp,
+        start..start + values.len(),
+        Some(&buffer),
+    )?;
+    Ok(written[..4 * values.len()]
+        .chunks(4)
+        .map(|row| u32::from_be_bytes(row.try_into().unwrap()))
+        .collect())
+}
+
+/// Sets the bits of the given value in a single customer-programmable OTP row (relative
+/// to row 36) and returns the resulting value of the row.
+///
+/// ⚠️ This operation is irreversible.
+pub(crate) fn write_customer_otp_row(
+    vcio: &Vcio,
+    row: usize,
+    value: u32,
+) -> Result<u32, io::Error> {
+    Ok(write_row_range(vcio, row, &[value])?[0])
 }
 
 /// Reads the device secret from the private key OTP registers (rows 56 to 63).
 ///
 /// This requires a more recent firmware than [`read_customer_otp`].
 pub(crate) fn read_private_key(vcio: &Vcio) -> Result<DeviceSecret, io::Error> {
-    send_request(vcio, Tag::GetPrivateKey, None)
+    log!(debug, "Reading private key OTP rows.");
+    send_request(vcio, Tag::GetPrivateKey, 0..OTP_ROWS, None)
 }
 
 /// Writes the device secret to the private key OTP registers (rows 56 to 63).
 ///
 /// ⚠️ This operation is irreversible.
 ///
-/// This requires a more recent firmware than [`write_customer_otp`].
+/// This requires a more recent firmware than [`write_customer_otp_rows`].
 pub(crate) fn write_private_key(vcio: &Vcio, value: &[u8; 32]) -> Result<DeviceSecret, io::Error> {
-    send_request(vcio, Tag::SetPrivateKey, Some(value))
+    log!(
+        warn,
+        "Irreversibly writing device secret to private key OTP rows."
+    );
+    send_request(vcio, Tag::SetPrivateKey, 0..OTP_ROWS, Some(value))
 }
 
 #[cfg(test)]
@@ -127,11 +296,15 @@ mod tests {
     pub fn test_request_encoding() {
         // Reading of OTP values.
         assert_eq!(
-            encode_request(Tag::GetCustomerOtp, None).as_slice(),
+            encode_request(Tag::GetCustomerOtp, 0..8, None)
+                .unwrap()
+                .as_slice(),
             [64, 0, 0x00030021, 40, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0]
         );
         assert_eq!(
-            encode_request(Tag::GetPrivateKey, None).as_slice(),
+            encode_request(Tag::GetPrivateKey, 0..8, None)
+                .unwrap()
+                .as_slice(),
             [64, 0, 0x00030081, 40, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0]
         );
 
@@ -144,10 +317,10 @@ mod tests {
                 0xFFFFFFFF, 0xAA00BB00, 0x12345678, 0x12121212,
             ],
             &mut value,
-        );
+        ).unwrap();
         #[rustfmt::skip]
         assert_eq!(
-            encode_request(Tag::SetCustomerOtp, Some(&value)).as_slice(),
+            encode_request(Tag::SetCustomerOtp, 0..8, Some(&value)).unwrap().as_slice(),
             [
                 64, 0, 0x00038021, 40, 0, 0, 8,
                 0xABABABAB, 0x1234ABCD, 0x00FF00FF, 0xDDAADDAA,
@@ -157,7 +330,7 @@ mod tests {
         );
         #[rustfmt::skip]
         assert_eq!(
-            encode_request(Tag::SetPrivateKey, Some(&value)).as_slice(),
+            encode_request(Tag::SetPrivateKey, 0..8, Some(&value)).unwrap().as_slice(),
             [
                 64, 0, 0x00038081, 40, 0, 0, 8,
                 0xABABABAB, 0x1234ABCD, 0x00FF00FF, 0xDDAADDAA,
@@ -165,5 +338,74 @@ mod tests {
                 0
             ]
         );
+
+        // Writing of a subset of the OTP rows.
+        #[rustfmt::skip]
+        assert_eq!(
+            encode_request(Tag::SetCustomerOtp, 2..5, Some(&value)).unwrap().as_slice(),
+            [
+                64, 0, 0x00038021, 40, 0, 2, 3,
+                0xABABABAB, 0x1234ABCD, 0x00FF00FF, 0, 0, 0, 0, 0,
+                0
+            ]
+        );
+        assert!(encode_request(Tag::GetCustomerOtp, 4..4, None).is_err());
+        assert!(encode_request(Tag::GetCustomerOtp, 4..9, None).is_err());
+    }
+
+    /// Tests copying bytes from `u32` buffers including mismatched lengths.
+    #[test]
+    pub fn test_copy_bytes() {
+        let mut bytes = [0; 8];
+        copy_bytes(&[0x01020304, 0xA0B0C0D0], &mut bytes).unwrap();
+        assert_eq!(bytes, [0x01, 0x02, 0x03, 0x04, 0xA0, 0xB0, 0xC0, 0xD0]);
+        copy_bytes(&[], &mut []).unwrap();
+        for len in [0, 3, 5, 12] {
+            let error = copy_bytes(&[0x01020304, 0xA0B0C0D0], &mut vec![0; len]).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    /// Tests checking that the firmware processed all requested rows.
+    #[test]
+    pub fn test_check_layout() {
+        let response = |start: u32, count: u32| {
+            [
+                64,
+                vcio::RESPONSE_SUCCESS,
+                0x00030021,
+                40,
+                vcio::RESPONSE_SUCCESS | 40,
+                start,
+                count,
+            ]
+        };
+        assert!(check_layout(&response(0, 8), 0..8).is_ok());
+        assert!(check_layout(&response(2, 3), 2..5).is_ok());
+        for (buffer, rows) in [
+            (response(0, 6), 0..8),
+            (response(1, 3), 2..5),
+            ([64, vcio::RESPONSE_SUCCESS, 0x00030021, 40, 40, 0, 8], 0..8),
+            (
+                [
+                    64,
+                    vcio::RESPONSE_SUCCESS,
+                    0x00030021,
+                    40,
+                    vcio::RESPONSE_SUCCESS | 16,
+                    0,
+                    8,
+                ],
+                0..8,
+            ),
+        ] {
+            let error = check_layout(&buffer, rows).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+            assert!(error.get_ref().unwrap().is::<UnsupportedLayout>());
+        }
+        let error = check_layout(&response(0, 6), 0..8).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("reports 6 OTP rows starting at row 0"));
     }
 }
@@ -1,25 +1,113 @@
 //! Low-level interface to Raspberry Pi's _Video Core IO_ (VCIO) device.
 
-use std::{io, path::Path};
-
-use nix::{
-    errno::Errno,
-    fcntl,
-    libc::{c_char, c_int},
-    sys::stat,
-    unistd,
+use std::{
+    io,
+    ops::Deref,
+    path::{Path, PathBuf},
 };
 
+use nix::{errno::Errno, fcntl, libc::c_int, sys::stat, unistd};
+
 /// The path to the VCIO device.
+#[cfg(not(feature = "mock-vcio"))]
 pub(crate) const VCIO_PATH: &str = "/dev/vcio";
 
+/// The path to the file standing in for the VCIO device when mocking it.
+///
+/// The file is only used for locking.
+#[cfg(feature = "mock-vcio")]
+pub(crate) const VCIO_PATH: &str = "/dev/null";
+
+/// The response code of the property interface indicating success.
+pub(crate) const RESPONSE_SUCCESS: u32 = 0x80000000;
+
+/// Error indicating that the property interface did not process a request successfully.
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("Request to VCIO property interface unsuccessful (0x{0:08X}).")]
+pub(crate) struct UnsuccessfulResponse(pub u32);
+
+/// Checks whether the property interface processed the request in `buffer` successfully.
+pub(crate) fn check_response(buffer: &[u32]) -> Result<(), io::Error> {
+    if buffer[1] != RESPONSE_SUCCESS {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            UnsuccessfulResponse(buffer[1]),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks whether the error has been caused by an unsuccessful response.
+pub(crate) fn is_unsuccessful_response(error: &io::Error) -> bool {
+    error
+        .get_ref()
+        .map(|inner| inner.is::<UnsuccessfulResponse>())
+        .unwrap_or(false)
+}
+
+/// Error indicating that accessing the VCIO device has been denied.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error(
+    "Permission denied accessing the VCIO device `{}`. Run with sufficient privileges, e.g., \
+     as root, or add the user to the group owning the device (usually `video`).",
+    path.display()
+)]
+pub(crate) struct PermissionDenied {
+    /// The path of the VCIO device.
+    pub path: PathBuf,
+}
+
+/// Checks whether the error has been caused by denied access to the VCIO device.
+pub(crate) fn is_permission_denied(error: &io::Error) -> bool {
+    error
+        .get_ref()
+        .map(|inner| inner.is::<PermissionDenied>())
+        .unwrap_or(false)
+}
+
 /// A handle to the VCIO device.
 #[derive(Debug)]
 pub(crate) struct Vcio {
     /// The underlying file descriptor.
     fd: c_int,
-    /// Indicates whether the VCIO device has been locked.
-    locked: bool,
+}
+
+/// A lock on the VCIO device which is released when dropped.
+///
+/// The lock borrows the [`Vcio`] handle it has been obtained with and dereferences to it
+/// such that requests can be sent while holding the lock. As the lock is tied to the
+/// guard's lifetime, it cannot leak on early returns.
+#[derive(Debug)]
+#[must_use = "the lock is released immediately when the guard is dropped"]
+pub(crate) struct VcioLock<'vcio> {
+    /// The locked VCIO device.
+    vcio: &'vcio Vcio,
+    /// Indicates whether the lock is exclusive.
+    exclusive: bool,
+}
+
+impl VcioLock<'_> {
+    /// Checks whether the lock is exclusive.
+    pub(crate) fn is_exclusive(&self) -> bool {
+        self.exclusive
+    }
+}
+
+impl Deref for VcioLock<'_> {
+    type Target = Vcio;
+
+    fn deref(&self) -> &Self::Target {
+        self.vcio
+    }
+}
+
+impl Drop for VcioLock<'_> {
+    fn drop(&mut self) {
+        log!(debug, "Releasing lock on VCIO device.");
+        // The lock is released when closing the file descriptor at the latest, hence,
+        // errors can safely be ignored.
+        let _ = self.vcio.flock(nix::libc::LOCK_UN);
+    }
 }
 
 impl Vcio {
@@ -30,23 +118,30 @@ impl Vcio {
 
     /// Opens a handle to the VCIO device.
     pub(crate) fn open() -> Result<Self, io::Error> {
+        Self::open_path(Path::new(VCIO_PATH))
+    }
+
+    /// Opens a handle to the VCIO device at the given path.
+    ///
+    /// This allows using devices at non-standard paths, e.g., shims for testing. When
+    /// mocking the VCIO device, the file at the path is only used for locking.
+    pub(crate) fn open_path(path: &Path) -> Result<Self, io::Error> {
+        log!(debug, "Opening VCIO device `{}`.", path.display());
         let flags = fcntl::OFlag::O_NONBLOCK;
         let mode = stat::Mode::empty();
-        fcntl::open(VCIO_PATH, flags, mode)
-            .map_err(to_io_error)
-            .map(|fd| Self { fd, locked: false })
-    }
-
-    /// Generates an error when the VCIO device has already been locked.
-    fn error_when_locked(&self) -> Result<(), io::Error> {
-        if self.locked {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                "VCIO device is already locked.",
-            ))
-        } else {
-            Ok(())
-        }
+        fcntl::open(path, flags, mode)
+            .map_err(|error| {
+                match error {
+                    Errno::EACCES | Errno::EPERM => {
+                        io::Error::new(
+                            io::ErrorKind::PermissionDenied,
+                            PermissionDenied { path: path.into() },
+                        )
+                    }
+                    error => to_io_error(error),
+                }
+            })
+            .map(|fd| Self { fd })
     }
 
     /// Obtains a shared lock on the VCIO device.
@@ -55,23 +150,18 @@ impl Vcio {
     ///
     /// This method does not block but may return [`io::ErrorKind::WouldBlock`].
     ///
-    /// Note that the lock is automatically released when [`Vcio`] is dropped.
+    /// The lock is released when the returned [`VcioLock`] is dropped.
     ///
     /// # Errors
     ///
-    /// Produces an error when the VCIO device is already locked (using this handle) or
-    /// the underlying call to `flock` fails.
-    #[allow(dead_code)] // Currently unused but may be useful in the future.
-    pub(crate) fn lock_shared(&mut self) -> Result<(), io::Error> {
-        self.error_when_locked()?;
-
-        let result = unsafe { nix::libc::flock(self.fd, nix::libc::LOCK_SH | nix::libc::LOCK_NB) };
-        if result != 0 {
-            Err(io::Error::last_os_error())
-        } else {
-            self.locked = true;
-            Ok(())
-        }
+    /// Produces an error when the underlying call to `flock` fails.
+    pub(crate) fn lock_shared(&self) -> Result<VcioLock<'_>, io::Error> {
+        self.flock(nix::libc::LOCK_SH | nix::libc::LOCK_NB)?;
+        log!(debug, "Obtained shared lock on VCIO device.");
+        Ok(VcioLock {
+            vcio: self,
+            exclusive: false,
+        })
     }
 
     /// Obtains an exclusive lock on the VCIO device.
@@ -80,44 +170,49 @@ impl Vcio {
     ///
     /// This method does not block but may return [`io::ErrorKind::WouldBlock`].
     ///
-    /// Note that the lock is automatically released when [`Vcio`] is dropped.
+    /// The lock is released when the returned [`VcioLock`] is dropped.
     ///
     /// # Errors
     ///
-    /// Produces an error when the VCIO device is already locked (using this handle) or
-    /// the underlying call to `flock` fails.
-    pub(crate) fn lock_exclusive(&mut self) -> Result<(), io::Error> {
-        self.error_when_locked()?;
-
-        let result = unsafe { nix::libc::flock(self.fd, nix::libc::LOCK_EX | nix::libc::LOCK_NB) };
-        if result != 0 {
-            Err(io::Error::last_os_error())
-        } else {
-            self.locked = true;
-            Ok(())
-        }
+    /// Produces an error when the underlying call to `flock` fails.
+    pub(crate) fn lock_exclusive(&self) -> Result<VcioLock<'_>, io::Error> {
+        self.flock(nix::libc::LOCK_EX | nix::libc::LOCK_NB)?;
+        log!(debug, "Obtained exclusive lock on VCIO device.");
+        Ok(VcioLock {
+            vcio: self,
+            exclusive: true,
+        })
     }
 
-    /// Releases the previously obtained lock on the VCIO device.
+    /// Obtains an exclusive lock on the VCIO device waiting for other processes to
+    /// release their locks.
+    ///
+    /// ⚠️ In contrast to [`Vcio::lock_exclusive`], this method blocks until the lock can
+    /// be obtained. It hangs indefinitely in case another process never releases its
+    /// lock and deadlocks in case the lock is held by another handle of the calling
+    /// process.
+    ///
+    /// The lock is released when the returned [`VcioLock`] is dropped.
     ///
     /// # Errors
     ///
-    /// Produces an error when the VCIO device has not been locked (using this handle) or
-    /// the underlying call to `flock` fails.
-    #[allow(dead_code)] // Currently unused but may be useful in the future.
-    pub(crate) fn unlock(&mut self) -> Result<(), io::Error> {
-        if !self.locked {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "VCIO device is not locked.",
-            ));
-        }
+    /// Produces an error when the underlying call to `flock` fails.
+    pub(crate) fn lock_exclusive_blocking(&self) -> Result<VcioLock<'_>, io::Error> {
+        log!(debug, "Waiting for exclusive lock on VCIO device.");
+        self.flock(nix::libc::LOCK_EX)?;
+        log!(debug, "Obtained exclusive lock on VCIO device.");
+        Ok(VcioLock {
+            vcio: self,
+            exclusive: true,
+        })
+    }
 
-        let result = unsafe { nix::libc::flock(self.fd, nix::libc::LOCK_UN) };
+    /// Applies the given `flock` operation to the VCIO device.
+    fn flock(&self, operation: c_int) -> Result<(), io::Error> {
+        let result = unsafe { nix::libc::flock(self.fd, operation) };
         if result != 0 {
             Err(io::Error::last_os_error())
         } else {
-            self.locked = false;
             Ok(())
         }
     }
@@ -127,7 +222,10 @@ impl Vcio {
     /// # Safety
     ///
     /// The provided `buffer` must be valid as required by the property interface.
+    #[cfg(not(feature = "mock-vcio"))]
     pub unsafe fn ioctl_property(&self, buffer: &mut [u32]) -> Result<c_int, io::Error> {
+        use nix::libc::c_char;
+
         // Violating this safety precondition will most likely cause UB.
         assert!(
             buffer[0] <= (buffer.len() * 4) as u32,
@@ -159,8 +257,25 @@ impl Vcio {
     }
 }
 
+#[cfg(feature = "mock-vcio")]
+impl Vcio {
+    /// Processes a request to the property interface using the in-memory mock.
+    ///
+    /// # Safety
+    ///
+    /// The provided `buffer` must be valid as required by the property interface.
+    pub unsafe fn ioctl_property(&self, buffer: &mut [u32]) -> Result<c_int, io::Error> {
+        assert!(
+            buffer[0] <= (buffer.len() * 4) as u32,
+            "Invalid buffer size. Buffer is smaller than indicated."
+        );
+        super::mock::ioctl_property(buffer)
+    }
+}
+
 impl Drop for Vcio {
     fn drop(&mut self) {
+        log!(debug, "Closing VCIO device (releasing any lock).");
         // Any errors here may leave the file descriptor in a kind of
         // undefined state, hence, we simply ignore them.
         let _ = unistd::close(self.fd);
@@ -171,3 +286,66 @@ impl Drop for Vcio {
 fn to_io_error(error: Errno) -> io::Error {
     io::Error::from_raw_os_error(error as i32)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests the detection of unsuccessful responses.
+    #[test]
+    pub fn test_check_response() {
+        assert!(check_response(&[8, RESPONSE_SUCCESS]).is_ok());
+        let error = check_response(&[8, 0x80000001]).unwrap_err();
+        assert!(is_unsuccessful_response(&error));
+        assert!(!is_unsuccessful_response(&io::Error::from(
+            io::ErrorKind::WouldBlock
+        )));
+    }
+
+    /// Tests the detection of denied access to the VCIO device.
+    #[test]
+    pub fn test_permission_denied() {
+        let path = Path::new("/dev/vcio-shim");
+        let error = io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            PermissionDenied { path: path.into() },
+        );
+        assert!(is_permission_denied(&error));
+        assert!(error.to_string().contains("sufficient privileges"));
+        assert!(error.to_string().contains("`/dev/vcio-shim`"));
+        assert!(!is_permission_denied(&io::Error::from(
+            io::ErrorKind::PermissionDenied
+        )));
+    }
+
+    /// Tests that locks are released when their guards are dropped.
+    #[test]
+    pub fn test_lock_guard() {
+        let path = std::env::temp_dir().join(format!("rpi-derive-key-{}.lock", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        let vcio = Vcio::open_path(&path).unwrap();
+        let other = Vcio::open_path(&path).unwrap();
+        let try_lock = || -> Result<(), io::Error> {
+            // The lock is released when returning early, e.g., via `?`.
+            let lock = other.lock_exclusive()?;
+            assert!(lock.is_exclusive());
+            Ok(())
+        };
+        {
+            let lock = vcio.lock_shared().unwrap();
+            assert!(!lock.is_exclusive());
+            assert!(other.lock_shared().is_ok());
+            assert_eq!(try_lock().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        }
+        try_lock().unwrap();
+        {
+            let _lock = vcio.lock_exclusive().unwrap();
+            assert_eq!(
+                other.lock_shared().unwrap_err().kind(),
+                io::ErrorKind::WouldBlock
+            );
+        }
+        try_lock().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
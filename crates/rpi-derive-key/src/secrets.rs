@@ -2,17 +2,280 @@
 
 use std::{
     fmt::Debug,
+    io,
     ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
 };
 
-use rand::Rng;
+use rand::{CryptoRng, RngCore};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// Indicates whether secrets may be stored in unprotected memory.
+static ALLOW_UNPROTECTED: AtomicBool = AtomicBool::new(false);
+
+/// Indicates whether secrets are stored in memory excluded from core dumps in case the
+/// memory cannot be protected from being swapped to disk.
+static DUMP_EXCLUSION_FALLBACK: AtomicBool = AtomicBool::new(false);
+
+/// Indicates whether the process is made non-dumpable while any secret is live.
+static DISABLE_CORE_DUMPS: AtomicBool = AtomicBool::new(false);
+
+/// The number of live secrets and the dumpable state to restore once there are none.
+struct LiveSecrets {
+    /// The number of live secrets.
+    count: usize,
+    /// The dumpable state of the process before it has been made non-dumpable.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    restore: Option<i32>,
+}
+
+/// The live secrets of the process.
+static LIVE_SECRETS: Mutex<LiveSecrets> = Mutex::new(LiveSecrets {
+    count: 0,
+    restore: None,
+});
+
+/// Registers a new live secret making the process non-dumpable if enabled.
+fn secret_created() {
+    let mut live = LIVE_SECRETS
+        .lock()
+        .unwrap_or_else(|error| error.into_inner());
+    live.count += 1;
+    #[cfg(target_os = "linux")]
+    if DISABLE_CORE_DUMPS.load(Ordering::Relaxed) && live.restore.is_none() {
+        use nix::libc;
+
+        // SAFETY: `PR_GET_DUMPABLE` and `PR_SET_DUMPABLE` do not access any memory.
+        let dumpable = unsafe { libc::prctl(libc::PR_GET_DUMPABLE) };
+        if dumpable > 0 && unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0) } == 0 {
+            live.restore = Some(dumpable);
+        }
+    }
+}
+
+/// Unregisters a live secret restoring the dumpable state if it was the last one.
+fn secret_dropped() {
+    let mut live = LIVE_SECRETS
+        .lock()
+        .unwrap_or_else(|error| error.into_inner());
+    live.count -= 1;
+    #[cfg(target_os = "linux")]
+    if live.count == 0 {
+        if let Some(dumpable) = live.restore.take() {
+            // SAFETY: `PR_SET_DUMPABLE` does not access any memory.
+            unsafe { nix::libc::prctl(nix::libc::PR_SET_DUMPABLE, dumpable) };
+        }
+    }
+}
+
+/// Enable or disable making the process non-dumpable while any secret is live.
+pub(crate) fn set_disable_core_dumps(enable: bool) {
+    DISABLE_CORE_DUMPS.store(enable, Ordering::Relaxed);
+}
+
+/// Allow or disallow storing secrets in unprotected memory in case the memory cannot be
+/// protected from being swapped to disk.
+pub(crate) fn set_allow_unprotected(enable: bool) {
+    ALLOW_UNPROTECTED.store(enable, Ordering::Relaxed);
+}
+
+/// Enable or disable storing secrets in memory excluded from core dumps in case the
+/// memory cannot be protected from being swapped to disk.
+pub(crate) fn set_dump_exclusion_fallback(enable: bool) {
+    DUMP_EXCLUSION_FALLBACK.store(enable, Ordering::Relaxed);
+}
+
+/// The protection of the memory storing a secret.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum MemoryProtection {
+    /// The memory is locked and thus protected from being swapped to disk.
+    Locked,
+    /// The memory could not be locked but is excluded from core dumps (see
+    /// [`set_dump_exclusion_fallback`][crate::set_dump_exclusion_fallback]).
+    ExcludedFromDumps,
+    /// The memory is unprotected (see
+    /// [`set_allow_unprotected_memory`][crate::set_allow_unprotected_memory]).
+    Unprotected,
+}
+
+/// The memory storing a secret.
+enum Storage<T> {
+    /// The secret is stored on the heap.
+    Boxed(Box<T>),
+    /// The secret is stored in a dedicated mapping.
+    #[cfg(target_os = "linux")]
+    Mapped(Mapping<T>),
+}
+
+impl<T: Default> Storage<T> {
+    /// Allocates the memory for a secret storing the default value of `T`.
+    ///
+    /// On Linux, each secret is stored in its own mapping excluded from core dumps. No
+    /// other memory of the process is ever excluded. In case the mapping cannot be
+    /// created, a warning is printed and the secret is stored on the heap.
+    fn new() -> Self {
+        #[cfg(target_os = "linux")]
+        match Mapping::new() {
+            Ok(mapping) => return Self::Mapped(mapping),
+            Err(error) => {
+                eprintln!(
+                    "Warning! Unable to exclude memory from core dumps. {}",
+                    error
+                );
+            }
+        }
+        Self::Boxed(Box::default())
+    }
+}
+
+impl<T> Deref for Storage<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Self::Boxed(value) => value,
+            #[cfg(target_os = "linux")]
+            Self::Mapped(mapping) => mapping,
+        }
+    }
+}
+
+impl<T> DerefMut for Storage<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            Self::Boxed(value) => value,
+            #[cfg(target_os = "linux")]
+            Self::Mapped(mapping) => mapping,
+        }
+    }
+}
+
+/// A value stored in a dedicated shared mapping of an anonymous memory file which is
+/// excluded from core dumps.
+///
+/// The memory file is created with `memfd_create` and only referenced by the mapping,
+/// i.e., it is released together with the mapping.
+#[cfg(target_os = "linux")]
+struct Mapping<T> {
+    /// The start of the mapping.
+    ptr: std::ptr::NonNull<T>,
+    /// The length of the mapping in bytes.
+    len: usize,
+}
+
+// SAFETY: The mapping is exclusively owned like a `Box`.
+#[cfg(target_os = "linux")]
+unsafe impl<T: Send> Send for Mapping<T> {}
+
+// SAFETY: The mapping is exclusively owned like a `Box`.
+#[cfg(target_os = "linux")]
+unsafe impl<T: Sync> Sync for Mapping<T> {}
+
+#[cfg(target_os = "linux")]
+impl<T: Default> Mapping<T> {
+    /// Creates a new mapping storing the default value of `T`.
+    fn new() -> Result<Self, io::Error> {
+        use nix::libc;
+
+        let page_size = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+            size if size > 0 => size as usize,
+            _ => return Err(io::Error::last_os_error()),
+        };
+        assert!(std::mem::align_of::<T>() <= page_size);
+        let len = (std::mem::size_of::<T>().max(1) + page_size - 1) / page_size * page_size;
+        let fd = unsafe {
+            // SAFETY: The name is a valid nul-terminated string.
+            libc::memfd_create(
+                b"rpi-derive-key-secret\0".as_ptr().cast(),
+                libc::MFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ptr = unsafe {
+            // SAFETY: The file descriptor is valid.
+            if libc::ftruncate(fd, len as libc::off_t) != 0 {
+                let error = io::Error::last_os_error();
+                libc::close(fd);
+                return Err(error);
+            }
+            // SAFETY: Creates a new mapping of the memory file.
+            let ptr = libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            );
+            // The mapping keeps the memory file alive.
+            libc::close(fd);
+            ptr
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let mapping = Self {
+            ptr: std::ptr::NonNull::new(ptr.cast()).expect("The mapping is never null."),
+            len,
+        };
+        // SAFETY: The range is exactly the mapping created above.
+        if unsafe { libc::madvise(ptr, len, libc::MADV_DONTDUMP) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe {
+            // SAFETY: The mapping is page-aligned and large enough to store a `T`.
+            mapping.ptr.as_ptr().write(T::default());
+        }
+        Ok(mapping)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl<T> Deref for Mapping<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: The mapping stores an initialized `T` for its entire lifetime.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl<T> DerefMut for Mapping<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: The mapping stores an initialized `T` for its entire lifetime.
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl<T> Drop for Mapping<T> {
+    fn drop(&mut self) {
+        unsafe {
+            // SAFETY: The mapping is valid and not used afterwards.
+            std::ptr::drop_in_place(self.ptr.as_ptr());
+            nix::libc::munmap(self.ptr.as_ptr().cast(), self.len);
+        }
+    }
+}
 
 /// A box for securely storing secrets.
 ///
 /// This type provides the following protection mechanisms:
 ///
-/// - When dropped the memory is overwritten with the default value.
-/// - On Linux, the memory is protected from being swapped to disk.
+/// - When dropped the memory is overwritten with zeros (using [`zeroize`]).
+/// - On Linux, the memory is protected from being swapped to disk. If this is impossible,
+///   the memory is optionally excluded from core dumps instead (see
+///   [`MemoryProtection`]).
+/// - On Linux, the secret is stored in a dedicated mapping excluded from core dumps and,
+///   optionally, the process is made non-dumpable while any secret is live (see
+///   [`set_disable_core_dumps`]).
 /// - [`Debug`] is always implemented but hides the secret.
 ///
 /// Note that there intentionally exists no explicit method constructing [`Secret`] from a
@@ -22,20 +285,54 @@ use rand::Rng;
 /// constructed in-place whenever possible.
 ///
 /// We use [`Secret`] when handling the device and group secret.
-pub(crate) struct Secret<T: Copy + Default>(Box<T>);
+pub(crate) struct Secret<T: Copy + Default + Zeroize> {
+    /// The memory storing the secret.
+    storage: Storage<T>,
+    /// The protection of the memory.
+    protection: MemoryProtection,
+}
 
-impl<T: Copy + Default> Secret<T> {
+impl<T: Copy + Default + Zeroize> Secret<T> {
     /// Creates a new [`Secret`] using the default value of `T`.
+    ///
+    /// # Panics
+    ///
+    /// Panics in case the memory cannot be protected (see [`Secret::try_new`]).
     pub fn new() -> Self {
-        Self(Box::default()).protect()
+        match Self::try_new() {
+            Ok(secret) => secret,
+            Err(error) => panic!("Unable to `mlock` memory. {}", error),
+        }
     }
 
-    /// Protects the underlying memory from being swapped to disk (on Linux only).
+    /// Tries to create a new [`Secret`] using the default value of `T`.
     ///
-    /// # Panics
+    /// # Errors
     ///
-    /// Panics in case the memory cannot be protected.
-    fn protect(self) -> Self {
+    /// Produces an error in case the memory cannot be protected and storing secrets in
+    /// unprotected memory has not been allowed.
+    pub fn try_new() -> Result<Self, io::Error> {
+        // Balanced by dropping the secret, also in case protecting it fails.
+        secret_created();
+        Self {
+            storage: Storage::new(),
+            protection: MemoryProtection::Unprotected,
+        }
+        .try_protect()
+    }
+
+    /// The protection of the memory storing the secret.
+    pub fn protection(&self) -> MemoryProtection {
+        self.protection
+    }
+
+    /// Protects the underlying memory from being swapped to disk (on Linux only).
+    ///
+    /// If this fails and the fallback has been enabled, it suffices that the secret is
+    /// stored in a mapping excluded from core dumps. If unprotected memory has been
+    /// allowed, failures are reported as a warning and the memory stays unprotected.
+    #[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+    fn try_protect(mut self) -> Result<Self, io::Error> {
         #[cfg(target_os = "linux")]
         {
             use std::ffi::c_void;
@@ -43,22 +340,31 @@ impl<T: Copy + Default> Secret<T> {
             let result = unsafe {
                 // SAFETY: Uses a valid allocation and the correct length.
                 nix::libc::mlock(
-                    self.0.as_ref() as *const _ as *const c_void,
-                    std::mem::size_of_val(self.0.as_ref()),
+                    self.storage.deref() as *const _ as *const c_void,
+                    std::mem::size_of::<T>(),
                 )
             };
-            if result != 0 {
-                panic!(
-                    "Unable to `mlock` memory. {}",
-                    std::io::Error::last_os_error()
-                );
+            if result == 0 {
+                self.protection = MemoryProtection::Locked;
+                return Ok(self);
+            }
+            let error = io::Error::last_os_error();
+            if DUMP_EXCLUSION_FALLBACK.load(Ordering::Relaxed)
+                && matches!(self.storage, Storage::Mapped(_))
+            {
+                self.protection = MemoryProtection::ExcludedFromDumps;
+                return Ok(self);
+            }
+            if !ALLOW_UNPROTECTED.load(Ordering::Relaxed) {
+                return Err(error);
             }
+            eprintln!("Warning! Unable to `mlock` memory. {}", error);
         }
-        self
+        Ok(self)
     }
 }
 
-impl<T: Copy + Default> From<&T> for Secret<T> {
+impl<T: Copy + Default + Zeroize> From<&T> for Secret<T> {
     fn from(value: &T) -> Self {
         let mut secret = Self::new();
         *secret = *value;
@@ -66,49 +372,51 @@ impl<T: Copy + Default> From<&T> for Secret<T> {
     }
 }
 
-impl<T: Copy + Default> Clone for Secret<T> {
+impl<T: Copy + Default + Zeroize> Clone for Secret<T> {
     fn clone(&self) -> Self {
-        Self(self.0.clone()).protect()
+        let mut secret = Self::new();
+        *secret = *self.deref();
+        secret
     }
 }
 
-impl<T: Copy + Default> Default for Secret<T> {
+impl<T: Copy + Default + Zeroize> Default for Secret<T> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl<T: Copy + Default> Debug for Secret<T> {
+impl<T: Copy + Default + Zeroize> Debug for Secret<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Secret").finish_non_exhaustive()
     }
 }
 
-impl<T: Copy + Default> Deref for Secret<T> {
+impl<T: Copy + Default + Zeroize> Deref for Secret<T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        self.0.deref()
+        self.storage.deref()
     }
 }
 
-impl<T: Copy + Default> DerefMut for Secret<T> {
+impl<T: Copy + Default + Zeroize> DerefMut for Secret<T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        self.0.deref_mut()
+        self.storage.deref_mut()
     }
 }
 
-impl<T: Copy + Default> Drop for Secret<T> {
+impl<T: Copy + Default + Zeroize> Drop for Secret<T> {
     fn drop(&mut self) {
-        unsafe {
-            // SAFETY: Underlying type `T` is copy. Hence, we can just overwrite it with
-            // its default value. Note that we have to use `write_volatile` here because
-            // we do not want the compiler to optimize this write.
-            std::ptr::write_volatile(self.0.as_mut(), T::default())
-        }
+        // Uses volatile writes and a compiler fence such that the compiler does not
+        // optimize the overwrite away.
+        self.storage.deref_mut().zeroize();
+        secret_dropped();
     }
 }
 
+impl<T: Copy + Default + Zeroize> ZeroizeOnDrop for Secret<T> {}
+
 /// Type of the group secret.
 pub(crate) type GroupSecret = Secret<[u8; 16]>;
 
@@ -116,20 +424,45 @@ pub(crate) type GroupSecret = Secret<[u8; 16]>;
 pub(crate) type DeviceSecret = Secret<[u8; 32]>;
 
 /// Randomly generates a device secret using a cryptographic random number generator.
+#[allow(dead_code)] // Only used in tests.
+pub(crate) fn generate_device_secret() -> Result<DeviceSecret, io::Error> {
+    generate_device_secret_with_rng(&mut rand::thread_rng())
+}
+
+/// Randomly generates a device secret using the provided cryptographic random number
+/// generator.
 #[allow(dead_code)] // Only used on Linux.
-pub(crate) fn generate_device_secret() -> DeviceSecret {
-    let mut secret = DeviceSecret::new();
-    rand::thread_rng().fill(secret.as_mut_slice());
-    secret
+pub(crate) fn generate_device_secret_with_rng<R: RngCore + CryptoRng + ?Sized>(
+    rng: &mut R,
+) -> Result<DeviceSecret, io::Error> {
+    let mut secret = DeviceSecret::try_new()?;
+    rng.try_fill_bytes(secret.as_mut_slice())
+        .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+    Ok(secret)
+}
+
+/// Randomly generates a device secret with the optional group secret in its upper
+/// 128-bits using the provided cryptographic random number generator.
+#[allow(dead_code)] // Only used on Linux.
+pub(crate) fn generate_device_secret_with_group<R: RngCore + CryptoRng + ?Sized>(
+    group_secret: Option<&[u8; 16]>,
+    rng: &mut R,
+) -> Result<DeviceSecret, io::Error> {
+    let mut secret = generate_device_secret_with_rng(rng)?;
+    if let Some(group_secret) = group_secret {
+        set_group_secret(&mut secret, group_secret);
+    }
+    Ok(secret)
 }
 
 /// Overwrites the upper 128-bits of the device secret with the provided group secret.
 #[allow(dead_code)] // Only used on Linux.
-pub(crate) fn set_group_secret(device_secret: &mut DeviceSecret, group_secret: &GroupSecret) {
+pub(crate) fn set_group_secret(device_secret: &mut DeviceSecret, group_secret: &[u8; 16]) {
     device_secret[..16].copy_from_slice(group_secret.as_slice());
 }
 
 /// Extracts and returns the group secret from the provided device secret.
+#[allow(dead_code)] // Only used in tests.
 pub(crate) fn get_group_secret(device_secret: &DeviceSecret) -> &[u8; 16] {
     device_secret[..16]
         .try_into()
@@ -160,7 +493,7 @@ mod tests {
     /// Tests the generation of a random secret with [`generate_device_secret`].
     #[test]
     fn test_generate_device_secret() {
-        let secret = generate_device_secret();
+        let secret = generate_device_secret().unwrap();
         // Technically, the randomly generated secret could be just zeros, however, the
         // probability of this happening is absolutely negligible.
         assert_ne!(secret.deref(), &[0; 32]);
@@ -170,7 +503,7 @@ mod tests {
     /// Tests [`set_group_secret`].
     #[test]
     fn test_set_group_secret() {
-        let mut secret = generate_device_secret();
+        let mut secret = generate_device_secret().unwrap();
         // Technically, the randomly generated secret could be just zeros, however, the
         // probability of this happening is absolutely negligible.
         assert_ne!(get_group_secret(&secret), &[0; 16]);
@@ -179,4 +512,37 @@ mod tests {
         assert_eq!(get_group_secret(&secret), &[0; 16]);
         assert_ne!(secret.deref(), &[0; 32]);
     }
+
+    /// Checks that [`Secret`] is zeroized on drop.
+    #[test]
+    fn test_secret_is_zeroize_on_drop() {
+        fn check<S: ZeroizeOnDrop>(_: &S) {}
+        check(&DeviceSecret::new());
+    }
+
+    /// Tests storing a secret in a mapping excluded from core dumps.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_mapping() {
+        let mut secret = DeviceSecret::try_new().unwrap();
+        assert!(matches!(secret.storage, Storage::Mapped(_)));
+        assert_eq!(secret.deref(), &[0; 32]);
+        secret.copy_from_slice(&[0xab; 32]);
+        assert_eq!(secret.deref(), &[0xab; 32]);
+        let clone = secret.clone();
+        assert_eq!(clone.deref(), &[0xab; 32]);
+        drop(secret);
+        assert_eq!(clone.deref(), &[0xab; 32]);
+    }
+
+    /// Tests that the process is non-dumpable while a secret is live.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_disable_core_dumps() {
+        set_disable_core_dumps(true);
+        let secret = DeviceSecret::new();
+        assert_eq!(unsafe { nix::libc::prctl(nix::libc::PR_GET_DUMPABLE) }, 0);
+        set_disable_core_dumps(false);
+        drop(secret);
+    }
 }
@@ -0,0 +1,42 @@
+//! Tests of the fake device secret provided via the environment.
+//!
+//! These tests modify the environment of the process and thus live in their own test
+//! binary such that they cannot interfere with other tests.
+
+#![cfg(feature = "std")]
+
+use rpi_derive_key::{Deriver, DeriverBuilder};
+
+/// The environment variable for providing a fake device secret.
+const FAKE_SECRET_VAR: &str = "FAKE_RPI_DERIVE_KEY_SECRET";
+
+/// The fake device secret used for testing.
+const SECRET: [u8; 32] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+    0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f,
+];
+
+/// Tests [`Deriver::try_from_env`] and its consistency with [`DeriverBuilder::build`].
+#[test]
+fn test_try_from_env() {
+    std::env::remove_var(FAKE_SECRET_VAR);
+    assert!(Deriver::try_from_env().is_none());
+
+    std::env::set_var(
+        FAKE_SECRET_VAR,
+        "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+    );
+    let deriver = Deriver::try_from_env().unwrap().unwrap();
+    let expected = Deriver::new_fake(None, &SECRET)
+        .derive_key_vec("info", 32)
+        .unwrap();
+    assert_eq!(deriver.derive_key_vec("info", 32).unwrap(), expected);
+    let built = DeriverBuilder::new().build().unwrap();
+    assert_eq!(built.derive_key_vec("info", 32).unwrap(), expected);
+
+    std::env::set_var(FAKE_SECRET_VAR, "00");
+    assert!(Deriver::try_from_env().unwrap().is_err());
+    assert!(DeriverBuilder::new().build().is_err());
+
+    std::env::remove_var(FAKE_SECRET_VAR);
+}
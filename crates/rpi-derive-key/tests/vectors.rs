@@ -0,0 +1,101 @@
+//! Test vectors guarding the key schedule against accidental changes.
+//!
+//! Changing any of these vectors changes the keys of all existing devices. The expected
+//! outputs have been computed independently with HKDF-SHA3-512 (RFC 5869).
+
+use rpi_derive_key::Deriver;
+
+/// The fake device secret used for the test vectors.
+const SECRET: [u8; 32] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+    0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f,
+];
+
+/// The salt used for the salted test vectors.
+const SALT: &[u8] = b"rpi-derive-key/salt";
+
+/// A test vector consisting of the salt, the info, and the expected hex-encoded device
+/// and group keys.
+struct Vector {
+    salt: Option<&'static [u8]>,
+    info: &'static str,
+    device_key: &'static str,
+    group_key: &'static str,
+}
+
+/// The test vectors.
+const VECTORS: &[Vector] = &[
+    Vector {
+        salt: None,
+        info: "",
+        device_key: "93a720279f48fd049f5f91d8e3fb751458bf63a07eeb3b5158d917d43c6988a2",
+        group_key: "43b6654fc1b9806cd2202db18744ebfb84b3c64bff685cefafad83c6e331e38f",
+    },
+    Vector {
+        salt: None,
+        info: "disk",
+        device_key: "df389aad731103c9690dbd5b59ab8bcb9bbbeefa8e8d3dfb27a720b335514a41",
+        group_key: "ebd979628491ed227e540c5ea7c3d9f501968c9b9a7fcee09b4f1d6df5dee6c4",
+    },
+    Vector {
+        salt: Some(b""),
+        info: "disk",
+        device_key: "df389aad731103c9690dbd5b59ab8bcb9bbbeefa8e8d3dfb27a720b335514a41",
+        group_key: "ebd979628491ed227e540c5ea7c3d9f501968c9b9a7fcee09b4f1d6df5dee6c4",
+    },
+    Vector {
+        salt: None,
+        info: "schlüssel/🔑",
+        device_key: "f50ed4473af57635a2fff93b0ca21cdb8c23a5ffdfc776143460040590746b75",
+        group_key: "fa2c1f3429062415fc35284d9e2c845e4cbb37b4caf1b854ec1bdd016b362ce1",
+    },
+    Vector {
+        salt: Some(SALT),
+        info: "",
+        device_key: "024576352099036245e5f0205e40e36ddcd7781a86876e20f51a69db2f2e5b1f",
+        group_key: "20e163bf83cc181ea2a08d3cc3781196065d32eebef585be756e325247d8f132",
+    },
+    Vector {
+        salt: Some(SALT),
+        info: "schlüssel/🔑",
+        device_key: "ac35165826e82659537c34bf11c5641f898bc03a1e8c003b7d2e8a09f32c3ad9",
+        group_key: "ab2e1387ce4a6ca960343219882807e13a9944d79ca14b47b4193cb206a83f12",
+    },
+    Vector {
+        salt: Some(SALT),
+        info: "disk",
+        device_key: "5b9b0f6197fd1b5e116854572d3bce3d034a753cd94a8430585dcb1baebb30fbb9f4a3cfc9d6fb5f\
+                     023500f9d81c63c9e67fe554505d289b05d96ec3a0807a36d2dd4eceb3595c5455f07c751b2a7c36\
+                     f10424568c218506d0c2ae67b0b713b153ba88dc",
+        group_key: "8b389c37a8fcecb3fe93f457a96a633d044bddae12958669b7d90cd5fe67446434ba52bdf0b60556\
+                    f4ee945ee8a447bae414bc6ee645f69ed55d4d6078e94a976d805970320c3b4423c3a9c68081120f\
+                    8c4eaa545aa936f65f69770f23209144dbfe1377",
+    },
+];
+
+/// Encodes the bytes as lowercase hex.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Tests that device keys match the test vectors.
+#[test]
+fn test_device_key_vectors() {
+    for vector in VECTORS {
+        let deriver = Deriver::new_fake(vector.salt, &SECRET);
+        let mut key = vec![0; vector.device_key.len() / 2];
+        deriver.derive_key(vector.info, &mut key).unwrap();
+        assert_eq!(hex(&key), vector.device_key, "info = {:?}", vector.info);
+    }
+}
+
+/// Tests that group keys match the test vectors.
+#[test]
+fn test_group_key_vectors() {
+    for vector in VECTORS {
+        let deriver = Deriver::new_fake(vector.salt, &SECRET);
+        let mut key = vec![0; vector.group_key.len() / 2];
+        deriver.derive_group_key(vector.info, &mut key).unwrap();
+        assert_eq!(hex(&key), vector.group_key, "info = {:?}", vector.info);
+    }
+}
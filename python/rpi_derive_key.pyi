@@ -1,5 +1,89 @@
+class BuildError(Exception):
+    """Unable to build a `Deriver`."""
+
+class UninitializedError(BuildError):
+    """Device-specific secret has not been initialized."""
+
 class DeriverBuilder:
+    use_customer_otp: bool
+
     def __init__(self) -> None: ...
+    def set_salt(self, salt: bytes | None) -> None:
+        """Sets the optional salt to use for the HKDF algorithm."""
+    def set_group_secret(self, secret: bytes) -> None:
+        """
+        Sets the group secret to use when initializing the device secret.
+
+        Raises `ValueError` in case the group secret is not exactly 16 bytes.
+        """
+    def set_initialize(self, enable: bool) -> None:
+        """
+        Enables the automatic initialization of the OTP memory with a randomly
+        generated secret.
+
+        ⚠️ The initialization is irreversible.
+        """
+    def build(self) -> Deriver:
+        """
+        Builds a `Deriver`.
+
+        Raises `UninitializedError` in case the device secret has not been initialized
+        and `BuildError` in case of any other error.
+        """
+    def __repr__(self) -> str:
+        """Returns a representation of the builder which never reveals any secrets."""
 
 class Deriver:
-    def derive_key(self, size: int, info: str) -> bytes: ...
\ No newline at end of file
+    """
+    Derives keys from the device secret.
+
+    The deriver should be used as context manager such that the underlying secrets are
+    wiped from memory deterministically when leaving the context:
+
+    ```
+    with rpi_derive_key.DeriverBuilder().build() as deriver:
+        key = deriver.derive_key(32, "info")
+    ```
+
+    Using a closed deriver raises `ValueError`.
+    """
+
+    def __enter__(self) -> Deriver: ...
+    def __exit__(self, *args: object) -> bool:
+        """Closes the deriver."""
+    def close(self) -> None:
+        """Closes the deriver wiping the underlying secrets from memory."""
+    def __repr__(self) -> str:
+        """Returns a representation of the deriver which never reveals any secrets."""
+    def derive_key(self, size: int, info: str) -> bytes:
+        """
+        Derives a device-specific key of the given size.
+
+        Raises `ValueError` in case the requested size is too large.
+        """
+    def derive_group_key(self, size: int, info: str) -> bytes:
+        """
+        Derives a group key of the given size.
+
+        Group keys are derived from the upper 128 bits of the device secret, i.e., the
+        group secret. Hence, all devices of a group derive the same group keys.
+
+        Raises `ValueError` in case the requested size is too large.
+        """
+
+class Status:
+    has_customer_otp: bool
+    has_private_key: bool
+
+def status() -> Status:
+    """
+    Returns the status of the OTP registers.
+
+    Raises `OSError` in case the status cannot be obtained.
+    """
+
+def is_raspberry_pi() -> bool:
+    """Checks whether the device is a Raspberry Pi."""
+
+def supports_private_key() -> bool:
+    """Checks whether the Raspberry Pi's firmware supports storing a private key."""
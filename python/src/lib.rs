@@ -1,4 +1,39 @@
-use pyo3::{prelude::*, types::PyBytes};
+#![allow(unexpected_cfgs)] // Required because the macros of PyO3 0.18 use unknown `cfg`s.
+
+use pyo3::{
+    create_exception,
+    exceptions::{PyException, PyValueError},
+    prelude::*,
+    types::PyBytes,
+};
+
+create_exception!(
+    rpi_derive_key,
+    BuildError,
+    PyException,
+    "Unable to build a `Deriver`."
+);
+create_exception!(
+    rpi_derive_key,
+    UninitializedError,
+    BuildError,
+    "Device-specific secret has not been initialized."
+);
+
+/// Converts a [`BuildError`][::rpi_derive_key::BuildError] into a Python exception.
+fn build_error_to_py(error: ::rpi_derive_key::BuildError) -> PyErr {
+    match error {
+        ::rpi_derive_key::BuildError::Uninitialized => {
+            UninitializedError::new_err(error.to_string())
+        }
+        _ => BuildError::new_err(error.to_string()),
+    }
+}
+
+/// Converts an [`InvalidLength`][::rpi_derive_key::InvalidLength] into a `ValueError`.
+fn invalid_length_to_py(error: ::rpi_derive_key::InvalidLength) -> PyErr {
+    PyValueError::new_err(error.to_string())
+}
 
 #[pyclass]
 struct DeriverBuilder(::rpi_derive_key::DeriverBuilder);
@@ -20,31 +55,134 @@ impl DeriverBuilder {
         self.0.set_use_customer_otp(enable)
     }
 
+    fn set_salt(&mut self, salt: Option<&[u8]>) {
+        self.0.set_salt(salt)
+    }
+
+    fn set_group_secret(&mut self, secret: &[u8]) -> PyResult<()> {
+        let secret: &[u8; 16] = secret
+            .try_into()
+            .map_err(|_| PyValueError::new_err("The group secret must be exactly 16 bytes."))?;
+        self.0.set_group_secret(secret);
+        Ok(())
+    }
+
+    fn set_initialize(&mut self, enable: bool) {
+        self.0.set_initialize(enable)
+    }
+
+    fn __repr__(&self) -> String {
+        // The `Debug` implementation never reveals any secrets.
+        format!("{:?}", self.0)
+    }
+
     fn build(&self) -> PyResult<Deriver> {
-        match self.0.clone().build() {
-            Ok(deriver) => Ok(Deriver(deriver)),
-            Err(_) => todo!(),
-        }
+        self.0
+            .clone()
+            .build()
+            .map(|deriver| Deriver(Some(deriver)))
+            .map_err(build_error_to_py)
     }
 }
 
+/// A deriver which is dropped, including its secrets, when it is closed.
 #[pyclass]
-struct Deriver(::rpi_derive_key::Deriver);
+struct Deriver(Option<::rpi_derive_key::Deriver>);
+
+impl Deriver {
+    /// The underlying deriver, if it has not been closed.
+    fn deriver(&self) -> PyResult<&::rpi_derive_key::Deriver> {
+        self.0
+            .as_ref()
+            .ok_or_else(|| PyValueError::new_err("The deriver has been closed."))
+    }
+}
 
 #[pymethods]
 impl Deriver {
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __exit__(&mut self, _exc_type: &PyAny, _exc_value: &PyAny, _traceback: &PyAny) -> bool {
+        self.close();
+        false
+    }
+
+    fn close(&mut self) {
+        self.0 = None;
+    }
+
+    fn __repr__(&self) -> String {
+        // The `Debug` implementation never reveals any secrets.
+        match &self.0 {
+            Some(deriver) => format!("{:?}", deriver),
+            None => "Deriver(closed)".to_owned(),
+        }
+    }
+
     fn derive_key<'py>(&self, py: Python<'py>, size: usize, info: &str) -> PyResult<&'py PyBytes> {
         let mut key = vec![0; size];
-        match self.0.derive_key(info, &mut key) {
+        match self.deriver()?.derive_key(info, &mut key) {
             Ok(_) => Ok(PyBytes::new(py, &key)),
-            Err(_) => todo!(),
+            Err(error) => Err(invalid_length_to_py(error)),
         }
     }
+
+    fn derive_group_key<'py>(
+        &self,
+        py: Python<'py>,
+        size: usize,
+        info: &str,
+    ) -> PyResult<&'py PyBytes> {
+        let mut key = vec![0; size];
+        match self.deriver()?.derive_group_key(info, &mut key) {
+            Ok(_) => Ok(PyBytes::new(py, &key)),
+            Err(error) => Err(invalid_length_to_py(error)),
+        }
+    }
+}
+
+#[pyclass]
+struct Status(::rpi_derive_key::Status);
+
+#[pymethods]
+impl Status {
+    #[getter]
+    fn has_customer_otp(&self) -> bool {
+        self.0.has_customer_otp
+    }
+
+    #[getter]
+    fn has_private_key(&self) -> bool {
+        self.0.has_private_key
+    }
+}
+
+#[pyfunction]
+fn status() -> PyResult<Status> {
+    Ok(Status(::rpi_derive_key::status()?))
+}
+
+#[pyfunction]
+fn is_raspberry_pi() -> bool {
+    ::rpi_derive_key::is_raspberry_pi()
+}
+
+#[pyfunction]
+fn supports_private_key() -> bool {
+    ::rpi_derive_key::supports_private_key()
 }
 
 #[pymodule]
-fn rpi_derive_key(_: Python, m: &PyModule) -> PyResult<()> {
+fn rpi_derive_key(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add("BuildError", py.get_type::<BuildError>())?;
+    m.add("UninitializedError", py.get_type::<UninitializedError>())?;
     m.add_class::<DeriverBuilder>()?;
     m.add_class::<Deriver>()?;
+    m.add_class::<Status>()?;
+    m.add_function(wrap_pyfunction!(status, m)?)?;
+    m.add_function(wrap_pyfunction!(is_raspberry_pi, m)?)?;
+    m.add_function(wrap_pyfunction!(supports_private_key, m)?)?;
     Ok(())
 }
@@ -0,0 +1,50 @@
+import pytest
+
+import rpi_derive_key
+
+FAKE_SECRET = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
+
+
+@pytest.fixture
+def deriver(monkeypatch: pytest.MonkeyPatch) -> rpi_derive_key.Deriver:
+    monkeypatch.setenv("FAKE_RPI_DERIVE_KEY_SECRET", FAKE_SECRET)
+    return rpi_derive_key.DeriverBuilder().build()
+
+
+def test_derive_key(deriver: rpi_derive_key.Deriver) -> None:
+    assert len(deriver.derive_key(32, "info")) == 32
+    assert deriver.derive_key(32, "info") == deriver.derive_key(32, "info")
+
+
+def test_derive_key_invalid_length(deriver: rpi_derive_key.Deriver) -> None:
+    with pytest.raises(ValueError):
+        deriver.derive_key(100000, "info")
+
+
+def test_derive_group_key(deriver: rpi_derive_key.Deriver) -> None:
+    assert len(deriver.derive_group_key(32, "info")) == 32
+    assert deriver.derive_group_key(32, "info") != deriver.derive_key(32, "info")
+
+
+def test_context_manager(monkeypatch: pytest.MonkeyPatch) -> None:
+    monkeypatch.setenv("FAKE_RPI_DERIVE_KEY_SECRET", FAKE_SECRET)
+    with rpi_derive_key.DeriverBuilder().build() as deriver:
+        assert len(deriver.derive_key(32, "info")) == 32
+    with pytest.raises(ValueError):
+        deriver.derive_key(32, "info")
+    with pytest.raises(ValueError):
+        deriver.derive_group_key(32, "info")
+
+
+def test_repr(monkeypatch: pytest.MonkeyPatch) -> None:
+    monkeypatch.setenv("FAKE_RPI_DERIVE_KEY_SECRET", FAKE_SECRET)
+    builder = rpi_derive_key.DeriverBuilder()
+    builder.set_group_secret(bytes(range(16)))
+    assert repr(builder).startswith("DeriverBuilder {")
+    assert "Secret { .. }" in repr(builder)
+    # A group secret is only accepted when initializing the device secret.
+    deriver = rpi_derive_key.DeriverBuilder().build()
+    assert repr(deriver).startswith("Deriver {")
+    assert repr(deriver).endswith(".. }")
+    deriver.close()
+    assert repr(deriver) == "Deriver(closed)"